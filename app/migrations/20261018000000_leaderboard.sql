@@ -0,0 +1,6 @@
+alter table score add column ex_score int;
+
+create table user_setting (
+    user int not null primary key,
+    leaderboard int not null default 0
+);
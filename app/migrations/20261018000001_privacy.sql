@@ -0,0 +1,10 @@
+alter table user_setting add column visibility text not null default 'public';
+alter table user_setting add column share_token text;
+create unique index user_setting_share_token on user_setting(share_token);
+
+create table rival (
+    id integer not null primary key autoincrement,
+    user int not null,
+    rival int not null
+);
+create unique index rival_index on rival(user, rival);
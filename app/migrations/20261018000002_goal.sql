@@ -0,0 +1,13 @@
+create table goal (
+    id integer not null primary key autoincrement,
+    user int not null,
+    name text,
+    target text not null,
+    value text not null,
+    level int,
+    difficulty int,
+    chart int,
+    count int,
+    created_at text not null
+);
+create index goal_user on goal(user);
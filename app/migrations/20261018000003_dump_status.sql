@@ -0,0 +1,8 @@
+create table dump_status (
+    user int not null primary key,
+    requested_at text,
+    succeeded_at text,
+    failed_at text,
+    attempts int not null default 0,
+    last_error text
+);
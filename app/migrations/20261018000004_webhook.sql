@@ -0,0 +1,20 @@
+create table webhook (
+    id integer not null primary key autoincrement,
+    user int not null,
+    url text not null,
+    secret text not null,
+    created_at text not null
+);
+create index webhook_user on webhook(user);
+
+create table webhook_delivery (
+    id integer not null primary key autoincrement,
+    webhook int not null,
+    delivery text not null,
+    event text not null,
+    attempt int not null,
+    status int,
+    error text,
+    created_at text not null
+);
+create index webhook_delivery_webhook on webhook_delivery(webhook);
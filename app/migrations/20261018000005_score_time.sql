@@ -0,0 +1,8 @@
+-- プレイ日時と登録日時を UNIX 時間 (秒, UTC) で持つ
+-- created_at は既存の出力との互換のため、プレイ日時の文字列として残す
+alter table score add column played_at int not null default 0;
+alter table score add column ingested_at int not null default 0;
+update score set
+    played_at = ifnull(cast(strftime('%s', created_at) as int), 0),
+    ingested_at = ifnull(cast(strftime('%s', created_at) as int), 0);
+create index score_user_played on score(user, played_at);
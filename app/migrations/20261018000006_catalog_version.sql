@@ -0,0 +1,6 @@
+-- 楽曲・譜面を更新したことを他のプロセスのキャッシュに知らせる
+create table catalog_version (
+    id int not null primary key check (id = 0),
+    version int not null
+);
+insert into catalog_version (id, version) values (0, 0);
@@ -0,0 +1,87 @@
+-- 重複と参照先のない行を取り除き、一意制約と外部キーを付ける
+
+-- 同名のユーザーは後から作られた方を `name#id` に改名する (記録は消さない)
+update user set name = name || '#' || id
+where exists (select 1 from user as u where u.name = user.name and u.id < user.id);
+create unique index user_name on user(name);
+
+-- 重複した譜面は ID の小さい方にまとめる
+create temp table chart_dup as
+select
+    c.id,
+    (select min(k.id) from chart as k
+        where k.song = c.song and k.play_type = c.play_type and k.difficulty = c.difficulty) as keep
+from chart as c;
+delete from chart_dup where id = keep;
+update score set chart = (select keep from chart_dup where chart_dup.id = score.chart)
+where chart in (select id from chart_dup);
+update best set chart = (select keep from chart_dup where chart_dup.id = best.chart)
+where chart in (select id from chart_dup);
+update goal set chart = (select keep from chart_dup where chart_dup.id = goal.chart)
+where chart in (select id from chart_dup);
+delete from chart where id in (select id from chart_dup);
+drop table chart_dup;
+
+-- 参照先のない行
+delete from chart where song not in (select id from song);
+delete from score where user not in (select id from user) or chart not in (select id from chart);
+delete from best
+where user not in (select id from user)
+    or chart not in (select id from chart)
+    or score not in (select id from score);
+
+-- 同じ譜面の自己ベストは最新の記録を指すものだけ残す
+delete from best
+where exists (
+    select 1 from best as b
+    where b.user = best.user and b.chart = best.chart
+        and (b.score > best.score or (b.score = best.score and b.id > best.id))
+);
+
+-- 外部キーは後から付けられないので作り直す (参照される側から順に)
+create table chart_new (
+    id integer not null primary key autoincrement,
+    song int not null references song(id),
+    play_type int not null,
+    difficulty int not null,
+    level int not null
+);
+insert into chart_new (id, song, play_type, difficulty, level)
+select id, song, play_type, difficulty, level from chart;
+drop table chart;
+alter table chart_new rename to chart;
+create index chart_level on chart(level);
+create unique index chart_song on chart(song, play_type, difficulty);
+
+create table score_new (
+    id integer not null primary key autoincrement,
+    user int not null references user(id),
+    chart int not null references chart(id),
+    score int,
+    ex_score int,
+    clear_rank text,
+    clear_kind text,
+    flare_rank int,
+    flare_skill int,
+    created_at text not null,
+    played_at int not null default 0,
+    ingested_at int not null default 0
+);
+insert into score_new (id, user, chart, score, ex_score, clear_rank, clear_kind, flare_rank, flare_skill, created_at, played_at, ingested_at)
+select id, user, chart, score, ex_score, clear_rank, clear_kind, flare_rank, flare_skill, created_at, played_at, ingested_at from score;
+drop table score;
+alter table score_new rename to score;
+create index score_index on score(chart);
+create index score_user_played on score(user, played_at);
+
+create table best_new (
+    id integer not null primary key autoincrement,
+    user int not null references user(id),
+    chart int not null references chart(id),
+    score int not null references score(id)
+);
+insert into best_new (id, user, chart, score)
+select id, user, chart, score from best;
+drop table best;
+alter table best_new rename to best;
+create unique index best_user on best(user, chart);
@@ -0,0 +1,7 @@
+-- 各プロセスのメトリクスを足し込む (private の /metrics で出力する)
+create table metric (
+    name text not null,
+    labels text not null,
+    value real not null,
+    primary key (name, labels)
+);
@@ -0,0 +1,5 @@
+-- 作り直しの依頼を DB に残し、Lambda が止まっても次の呼び出しで続きから実行する
+-- (next_attempt_at が null でなければ実行待ち、unix 時刻)
+alter table dump_status add column options text;
+alter table dump_status add column next_attempt_at int;
+create index dump_status_next_attempt on dump_status(next_attempt_at);
@@ -0,0 +1,11 @@
+-- 送信待ち・再送待ちの通知 (送れたか諦めたら消す、next_attempt_at は unix 時刻)
+create table webhook_job (
+    id integer not null primary key autoincrement,
+    webhook int not null,
+    delivery text not null,
+    body blob not null,
+    attempts int not null default 0,
+    next_attempt_at int not null,
+    created_at text not null
+);
+create index webhook_job_next_attempt on webhook_job(next_attempt_at);
@@ -0,0 +1,4 @@
+-- 実行中の依頼を next_attempt_at と分けて記録し、他のプロセスが実行中の依頼を待たないようにする
+-- (lease_until が未来なら実行中、unix 時刻)
+alter table dump_status add column lease_until int;
+alter table webhook_job add column lease_until int;
@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::{
+        schema::{ObjectBuilder, Type},
+        ContentBuilder, OpenApi, ResponseBuilder,
+    },
+    IntoParams, Modify, ToSchema,
+};
+
+use crate::{
+    dump::DumpFile,
+    export::ExportOptions,
+    goals::{CompletedGoal, NewGoal},
+    leaderboard::LeaderboardKey,
+    recommend::RecommendFilter,
+    scores::{Improvement, ImprovementSummary, RequestScoreData},
+    settings::UserSettingsPatch,
+    stats::StatsGroup,
+    timeline::TimelineFilter,
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct UpdateScoreRequest {
+    pub user: String,
+    pub password: String,
+    pub scores: Vec<RequestScoreData>,
+    /// 更新があればバックグラウンドで公開ダンプを作り直す
+    #[serde(default)]
+    pub dump: bool,
+    /// 公開ダンプの形式 (`columns` は指定できない)
+    #[serde(default)]
+    pub dump_options: ExportOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateScoreResponse {
+    pub updated: usize,
+    pub errors: Vec<String>,
+    /// 自己ベストを更新した譜面
+    pub improvements: Vec<Improvement>,
+    pub summary: ImprovementSummary,
+    pub completed_goals: Vec<CompletedGoal>,
+    /// ダンプの作り直しを依頼したか
+    pub dump_queued: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ImportRequest {
+    pub user: String,
+    pub password: String,
+    pub format: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DumpRequest {
+    pub user: String,
+    pub password: String,
+    /// 公開ダンプの形式 (`columns` は指定できない)
+    #[serde(flatten)]
+    pub options: ExportOptions,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DumpStatusRequest {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct UserDataRequest {
+    pub user: String,
+    pub password: String,
+    pub target: Option<String>,
+    pub file: DumpFile,
+    #[serde(flatten)]
+    pub options: ExportOptions,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct UpdateSettingsRequest {
+    pub user: String,
+    pub password: String,
+    #[serde(flatten)]
+    pub settings: UserSettingsPatch,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    pub by: LeaderboardKey,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct GoalsRequest {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AddGoalRequest {
+    pub user: String,
+    pub password: String,
+    pub goal: NewGoal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AddGoalResponse {
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DeleteGoalRequest {
+    pub user: String,
+    pub password: String,
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct WebhooksRequest {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AddWebhookRequest {
+    pub user: String,
+    pub password: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DeleteWebhookRequest {
+    pub user: String,
+    pub password: String,
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DeliveriesRequest {
+    pub user: String,
+    pub password: String,
+    /// 省略するとすべての Webhook
+    pub id: Option<i64>,
+    #[serde(default = "default_deliveries_limit")]
+    #[schema(default = 50)]
+    pub limit: i64,
+}
+
+fn default_deliveries_limit() -> i64 {
+    50
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct RecommendRequest {
+    pub user: String,
+    pub password: String,
+    #[serde(flatten)]
+    pub filter: RecommendFilter,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    #[serde(default)]
+    pub group_by: StatsGroup,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct StatsRequest {
+    pub user: String,
+    pub password: String,
+    pub target: Option<String>,
+    #[serde(default)]
+    pub group_by: StatsGroup,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TimelineRequest {
+    pub user: String,
+    pub password: String,
+    pub target: Option<String>,
+    #[serde(flatten)]
+    pub filter: TimelineFilter,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AddUserRequest {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct RequestSongData {
+    pub name: String,
+    pub version: String,
+    /// BEGINNER から CHALLENGE までの譜面のレベル (無い譜面は null)
+    pub levels: [Option<i64>; 5],
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AddSongsRequest {
+    pub songs: Vec<RequestSongData>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct AddSongsResponse {
+    pub inserted_songs: usize,
+    pub inserted_charts: usize,
+    pub updated_charts: usize,
+}
+
+/// すべての操作に `ApiError` の応答 (403・404・409・500、本文はテキスト) を足す
+pub struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut OpenApi) {
+        let text = |description: &str| {
+            ResponseBuilder::new()
+                .description(description)
+                .content(
+                    "text/plain",
+                    ContentBuilder::new()
+                        .schema(Some(ObjectBuilder::new().schema_type(Type::String)))
+                        .build(),
+                )
+                .build()
+        };
+        for item in openapi.paths.paths.values_mut() {
+            for op in [&mut item.get, &mut item.post].into_iter().flatten() {
+                op.responses
+                    .responses
+                    .entry("403".to_owned())
+                    .or_insert_with(|| text("Not allowed to view the user's data").into());
+                op.responses
+                    .responses
+                    .entry("404".to_owned())
+                    .or_insert_with(|| text("Unknown user, chart or resource").into());
+                op.responses
+                    .responses
+                    .entry("409".to_owned())
+                    .or_insert_with(|| text("Conflict with existing data").into());
+                op.responses
+                    .responses
+                    .entry("500".to_owned())
+                    .or_insert_with(|| text("Something went wrong").into());
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    ConnectOptions, Connection,
+};
+use utoipa::ToSchema;
+
+use crate::{
+    export::{Encoder, ExportCompression},
+    storage::Storage,
+};
+
+const BACKUP_DIR: &str = "backups";
+const BACKUP_SUFFIX: &str = ".db.gz";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BackupInfo {
+    pub key: String,
+    pub size: u64,
+    /// 保持数を超えたため削除したバックアップ
+    pub pruned: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoredBackup {
+    pub key: String,
+    /// バックアップに含まれる最新のマイグレーション
+    pub migration: Option<i64>,
+    /// 置き換える前のデータベースの退避先
+    pub previous: Option<PathBuf>,
+}
+
+/// `DATABASE_URL` からデータベースファイルのパスを得る
+pub fn db_path(db_url: &str) -> Result<PathBuf> {
+    Ok(SqliteConnectOptions::from_str(db_url)?
+        .get_filename()
+        .to_path_buf())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// 整合性と主要なテーブルの有無を確認し、最新のマイグレーションのバージョンを返す
+async fn verify(path: &Path) -> Result<Option<i64>> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await?;
+
+    let check = sqlx::query_scalar::<_, String>("pragma integrity_check")
+        .fetch_all(&mut conn)
+        .await?;
+    if check != ["ok"] {
+        return Err(anyhow!("Integrity check failed: {}", check.join("; ")));
+    }
+
+    let tables =
+        sqlx::query_scalar::<_, String>("select name from sqlite_master where type = 'table'")
+            .fetch_all(&mut conn)
+            .await?;
+    for t in ["user", "song", "chart", "score", "best"] {
+        if !tables.iter().any(|n| n == t) {
+            return Err(anyhow!("Missing table: {}", t));
+        }
+    }
+    let migration = if tables.iter().any(|n| n == "_sqlx_migrations") {
+        sqlx::query_scalar::<_, Option<i64>>(
+            "select max(version) from _sqlx_migrations where success = 1",
+        )
+        .fetch_one(&mut conn)
+        .await?
+    } else {
+        None
+    };
+
+    conn.close().await?;
+    Ok(migration)
+}
+
+fn compress_file(src: &Path, dest: &Path) -> Result<u64> {
+    let mut r = std::fs::File::open(src)?;
+    let mut enc = Encoder::new(std::fs::File::create(dest)?, ExportCompression::Gzip)?;
+    std::io::copy(&mut r, &mut enc)?;
+    let f = enc.finish()?;
+    f.sync_all()?;
+    Ok(f.metadata()?.len())
+}
+
+fn decompress_file(src: &Path, dest: &Path) -> Result<()> {
+    let mut r = GzDecoder::new(std::fs::File::open(src)?);
+    let mut f = std::fs::File::create(dest)?;
+    std::io::copy(&mut r, &mut f)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+/// 保存済みのバックアップを古い順に返す
+pub async fn list_backups(storage: &Storage) -> Result<Vec<String>> {
+    Ok(storage
+        .list(BACKUP_DIR)
+        .await?
+        .into_iter()
+        .filter(|k| k.ends_with(BACKUP_SUFFIX))
+        .collect())
+}
+
+/// 稼働中のデータベースを `VACUUM INTO` で複製し、圧縮して保存する
+///
+/// 新しいものから `keep` 件を残して古いバックアップは削除する
+pub async fn create_backup(
+    pool: &SqlitePool,
+    storage: &Storage,
+    keep: usize,
+) -> Result<BackupInfo> {
+    let name = format!("ddr_score-{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let snapshot = std::env::temp_dir().join(format!("{}.db", name));
+    let compressed = with_suffix(&snapshot, ".gz");
+    let key = format!("{}/{}{}", BACKUP_DIR, name, BACKUP_SUFFIX);
+
+    // VACUUM INTO は出力先が既にあると失敗する
+    remove_if_exists(&snapshot).await?;
+    let res = async {
+        let path = snapshot
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid temporary path: {:?}", snapshot))?;
+        sqlx::query("vacuum into ?")
+            .bind(path)
+            .execute(pool)
+            .await?;
+        verify(&snapshot).await?;
+
+        let (src, dest) = (snapshot.clone(), compressed.clone());
+        let size = tokio::task::spawn_blocking(move || compress_file(&src, &dest)).await??;
+        storage.put_file(&key, &compressed).await?;
+        Ok::<_, anyhow::Error>(size)
+    }
+    .await;
+    remove_if_exists(&snapshot).await?;
+    remove_if_exists(&compressed).await?;
+    let size = res?;
+
+    let backups = list_backups(storage).await?;
+    let mut pruned = vec![];
+    for old in &backups[..backups.len().saturating_sub(keep.max(1))] {
+        storage.delete(old).await?;
+        pruned.push(old.clone());
+    }
+
+    Ok(BackupInfo { key, size, pruned })
+}
+
+/// バックアップを取り出して整合性を確認し、データベースファイルと入れ替える
+///
+/// 入れ替え前のファイルは `{db}.{日時}.bak` として残す。
+/// 実行中は API を止めておくこと
+pub async fn restore_backup(storage: &Storage, key: &str, db: &Path) -> Result<RestoredBackup> {
+    // rename で入れ替えるため同じディレクトリに展開する
+    let compressed = with_suffix(db, ".restore.gz");
+    let restored = with_suffix(db, ".restore");
+
+    let res = async {
+        storage.get_file(key, &compressed).await?;
+        let (src, dest) = (compressed.clone(), restored.clone());
+        tokio::task::spawn_blocking(move || decompress_file(&src, &dest)).await??;
+        verify(&restored).await
+    }
+    .await;
+    remove_if_exists(&compressed).await?;
+    let migration = match res {
+        Ok(m) => m,
+        Err(e) => {
+            remove_if_exists(&restored).await?;
+            return Err(e);
+        }
+    };
+
+    let previous = if tokio::fs::try_exists(db).await? {
+        let bak = with_suffix(db, &format!(".{}.bak", Utc::now().format("%Y%m%dT%H%M%SZ")));
+        tokio::fs::rename(db, &bak).await?;
+        // 古いジャーナルが新しいファイルに適用されないよう一緒に退避する
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let side = with_suffix(db, suffix);
+            if tokio::fs::try_exists(&side).await? {
+                tokio::fs::rename(&side, with_suffix(&bak, suffix)).await?;
+            }
+        }
+        Some(bak)
+    } else {
+        None
+    };
+    tokio::fs::rename(&restored, db).await?;
+
+    Ok(RestoredBackup {
+        key: key.to_owned(),
+        migration,
+        previous,
+    })
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use app::{backup, storage::Storage};
+use sqlx::sqlite::SqlitePoolOptions;
+
+const USAGE: &str = "usage: backup create [--keep N] | backup list | backup restore KEY";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let db_url = std::env::var("DATABASE_URL").unwrap_or("sqlite:./.db/ddr_score.db".to_string());
+    let storage_url = std::env::var("BACKUP_STORAGE").unwrap_or("./.db/backups".to_string());
+    let mut keep = match std::env::var("BACKUP_KEEP") {
+        Ok(v) => v.parse()?,
+        Err(_) => 7,
+    };
+
+    let storage = Storage::from_url(&storage_url).await?;
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("create") => {
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--keep" => {
+                        keep = args.next().ok_or_else(|| anyhow!("{}", USAGE))?.parse()?;
+                    }
+                    _ => return Err(anyhow!("{}", USAGE)),
+                }
+            }
+
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .acquire_timeout(Duration::from_secs(10))
+                .connect(&db_url)
+                .await?;
+            let info = backup::create_backup(&pool, &storage, keep).await?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Some("list") => {
+            for key in backup::list_backups(&storage).await? {
+                println!("{}", key);
+            }
+        }
+        Some("restore") => {
+            let key = args.next().ok_or_else(|| anyhow!("{}", USAGE))?;
+            let db = backup::db_path(&db_url)?;
+            let restored = backup::restore_backup(&storage, &key, &db).await?;
+            println!("{}", serde_json::to_string_pretty(&restored)?);
+        }
+        _ => return Err(anyhow!("{}", USAGE)),
+    }
+
+    Ok(())
+}
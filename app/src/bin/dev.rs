@@ -0,0 +1,71 @@
+//! ローカル開発用に public と private を 1 つのサーバーで動かす (AWS 不要)
+//!
+//! - DB は `DEV_DATABASE_URL` (既定は `./.db/dev.db`)。無ければ作り、マイグレーションを適用する
+//! - 公開ダンプ・バックアップは `DEV_STORAGE_DIR` (既定は `./.db/dev`) 以下に置く
+//! - `DEV_SEED_SONGS` (既定は `./seed/songs.json`) があれば add_songs と同じ形式の楽曲一覧を読み込む
+//! - 待ち受けるアドレスは `DEV_ADDR` (既定は `127.0.0.1:8080`)
+//! - Webhook はループバック・プライベートアドレスには送らない。ローカルの受け口に送るときは
+//!   `WEBHOOK_ALLOWED_HOSTS=127.0.0.1,localhost` のように許可する
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use app::{
+    api::AddSongsRequest,
+    catalog,
+    db::{self, DbConfig},
+    metrics::Metrics,
+    server::{self, private, public},
+    storage::Storage,
+    telemetry,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    telemetry::init_tracing();
+
+    let url = std::env::var("DEV_DATABASE_URL").unwrap_or("sqlite:./.db/dev.db".to_string());
+    db::migrate(&url).await?;
+    let db = db::connect(&DbConfig {
+        url,
+        ..DbConfig::from_env()?
+    })
+    .await?;
+
+    let seed = std::env::var("DEV_SEED_SONGS").unwrap_or("./seed/songs.json".to_string());
+    if std::fs::exists(&seed)? {
+        let req: AddSongsRequest = serde_json::from_slice(&std::fs::read(&seed)?)?;
+        let res = catalog::add_songs(&db.writer, &req.songs).await?;
+        tracing::info!(
+            seed,
+            inserted_songs = res.inserted_songs,
+            inserted_charts = res.inserted_charts,
+            updated_charts = res.updated_charts,
+            "loaded seed songs"
+        );
+    }
+
+    let storage_dir =
+        PathBuf::from(std::env::var("DEV_STORAGE_DIR").unwrap_or("./.db/dev".to_string()));
+    let storage = Storage::Local(storage_dir.join("public"));
+    // バックアップは `backups/` 以下に置かれる
+    let backup_storage = Storage::Local(storage_dir);
+
+    // /metrics で両方の分を出すため、メトリクスは共有する
+    let metrics = Metrics::default();
+    let writer = db.writer.clone();
+    let public_state = public::AppState::new(db.clone(), storage, metrics.clone()).await?;
+    let private_state = private::AppState::new(db, Some(backup_storage), metrics.clone());
+    let app = public::router(public_state).merge(private::router(private_state));
+
+    let addr = std::env::var("DEV_ADDR").unwrap_or("127.0.0.1:8080".to_string());
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    tracing::info!(addr, "listening");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(server::shutdown_signal())
+        .await?;
+
+    metrics.flush(&writer).await?;
+
+    Ok(())
+}
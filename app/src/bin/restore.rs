@@ -0,0 +1,63 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use app::{catalog::CatalogData, restore};
+use sqlx::sqlite::SqlitePoolOptions;
+
+const USAGE: &str =
+    "usage: restore --user NAME [--scores scores.tsv.gz] [--bests bests.tsv.gz] [--dry-run]";
+
+fn file_name(path: &str) -> Result<&str> {
+    Path::new(path)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or_else(|| anyhow!("Invalid path: {}", path))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut user = None;
+    let mut scores_path = None;
+    let mut bests_path = None;
+    let mut dry_run = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--user" => user = args.next(),
+            "--scores" => scores_path = args.next(),
+            "--bests" => bests_path = args.next(),
+            "--dry-run" => dry_run = true,
+            _ => return Err(anyhow!("{}", USAGE)),
+        }
+    }
+    let user = user.ok_or_else(|| anyhow!("{}", USAGE))?;
+
+    let history = match &scores_path {
+        Some(p) => restore::parse_scores(&std::fs::read(p)?, file_name(p)?)?,
+        None => vec![],
+    };
+    let bests = match &bests_path {
+        Some(p) => restore::parse_bests(&std::fs::read(p)?, file_name(p)?)?,
+        None => vec![],
+    };
+
+    let db_url = std::env::var("DATABASE_URL").unwrap_or("sqlite:./.db/ddr_score.db".to_string());
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_secs(10))
+        .connect(&db_url)
+        .await?;
+
+    let user_id = sqlx::query!(r"select id from user where name = ?", user)
+        .fetch_one(&pool)
+        .await?
+        .id;
+
+    let catalog = CatalogData::load(&pool).await?;
+    let report = restore::restore(&pool, &catalog, user_id, &history, &bests, dry_run).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
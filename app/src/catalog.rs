@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::{SqliteExecutor, SqlitePool};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    api::{AddSongsResponse, RequestSongData},
+    Difficulty,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Song {
+    pub id: i64,
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Chart {
+    pub id: i64,
+    pub song: i64,
+    pub title: String,
+    pub play_type: i64,
+    pub difficulty: i64,
+    pub level: i64,
+}
+
+/// 曲名の表記ゆれを吸収したキー (NFKC・小文字・空白の連続を 1 つに)
+pub fn normalize_title(title: &str) -> String {
+    title
+        .nfkc()
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// ある時点の楽曲・譜面の一覧
+#[derive(Debug, Default)]
+pub struct CatalogData {
+    /// 読み込んだときの `catalog_version`
+    pub version: i64,
+    songs: Vec<Song>,
+    /// ID 順
+    charts: Vec<Chart>,
+    song_by_name: HashMap<String, usize>,
+    song_by_key: HashMap<String, usize>,
+    chart_by_id: HashMap<i64, usize>,
+    chart_by_song: HashMap<(i64, i64), usize>,
+}
+
+impl CatalogData {
+    pub async fn load(pool: &SqlitePool) -> Result<Self> {
+        let mut tx = pool.begin().await?;
+        let version = current_version(&mut *tx).await?;
+        let songs = sqlx::query_as!(
+            Song,
+            r#"select id, name, ver as "version" from song order by id"#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let charts = sqlx::query_as!(
+            Chart,
+            r"select
+                chart.id,
+                chart.song,
+                song.name as title,
+                chart.play_type,
+                chart.difficulty,
+                chart.level
+            from
+                chart
+            inner join song on song.id = chart.song
+            order by chart.id"
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let mut data = Self {
+            version,
+            ..Default::default()
+        };
+        for (i, s) in songs.iter().enumerate() {
+            data.song_by_name.insert(s.name.clone(), i);
+            data.song_by_key
+                .entry(normalize_title(&s.name))
+                .or_insert(i);
+        }
+        for (i, c) in charts.iter().enumerate() {
+            data.chart_by_id.insert(c.id, i);
+            data.chart_by_song.insert((c.song, c.difficulty), i);
+        }
+        data.songs = songs;
+        data.charts = charts;
+        Ok(data)
+    }
+
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+
+    /// ID 順の譜面
+    pub fn charts(&self) -> &[Chart] {
+        &self.charts
+    }
+
+    /// 曲名で探す (完全一致がなければ表記ゆれを吸収して探す)
+    pub fn song(&self, title: &str) -> Option<&Song> {
+        self.song_by_name
+            .get(title)
+            .or_else(|| self.song_by_key.get(&normalize_title(title)))
+            .map(|&i| &self.songs[i])
+    }
+
+    pub fn chart(&self, chart_id: i64) -> Option<&Chart> {
+        self.chart_by_id.get(&chart_id).map(|&i| &self.charts[i])
+    }
+
+    pub fn find_chart(&self, title: &str, difficulty: Difficulty) -> Option<&Chart> {
+        let song = self.song(title)?;
+        self.chart_by_song
+            .get(&(song.id, difficulty as i64))
+            .map(|&i| &self.charts[i])
+    }
+}
+
+async fn current_version(executor: impl SqliteExecutor<'_>) -> Result<i64> {
+    Ok(
+        sqlx::query!(r"select version from catalog_version where id = 0")
+            .fetch_optional(executor)
+            .await?
+            .map_or(0, |r| r.version),
+    )
+}
+
+/// 楽曲・譜面を更新したときに呼び、各プロセスのキャッシュを読み直させる
+pub async fn bump_version(executor: impl SqliteExecutor<'_>) -> Result<()> {
+    sqlx::query!(r"update catalog_version set version = version + 1 where id = 0")
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// 楽曲・譜面を追加し、既存の譜面はレベルを更新する (変更があれば `catalog_version` を上げる)
+pub async fn add_songs(pool: &SqlitePool, songs: &[RequestSongData]) -> Result<AddSongsResponse> {
+    let mut res = AddSongsResponse::default();
+
+    let cur_songs = sqlx::query!("select id, name, ver from song")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|s| (s.name, s.id))
+        .collect::<HashMap<_, _>>();
+    let cur_charts = sqlx::query!("select id, song, difficulty, level from chart")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|c| ((c.song, c.difficulty), (c.id, c.level)))
+        .collect::<HashMap<_, _>>();
+
+    let mut tx = pool.begin().await?;
+
+    for s in songs {
+        let song_id = if let Some(id) = cur_songs.get(&s.name) {
+            *id
+        } else {
+            let ss = sqlx::query!(
+                "insert into song (name, ver) values (?, ?) returning id",
+                s.name,
+                s.version
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            res.inserted_songs += 1;
+            ss.id
+        };
+        for dif in 0..5 {
+            let Some(level) = s.levels[dif] else {
+                continue;
+            };
+            let dif = dif as i64;
+            if let Some(&(chart_id, cur_level)) = cur_charts.get(&(song_id, dif)) {
+                if level == cur_level {
+                    continue;
+                }
+                sqlx::query!("update chart set level = ? where id = ?", level, chart_id)
+                    .execute(&mut *tx)
+                    .await?;
+                res.updated_charts += 1;
+            } else {
+                sqlx::query!(
+                    "insert into chart (song, play_type, difficulty, level) values (?, 1, ?, ?)",
+                    song_id,
+                    dif,
+                    level
+                )
+                .execute(&mut *tx)
+                .await?;
+                res.inserted_charts += 1;
+            }
+        }
+    }
+
+    if res.inserted_songs + res.inserted_charts + res.updated_charts > 0 {
+        bump_version(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(res)
+}
+
+/// 楽曲・譜面のキャッシュ
+///
+/// `current` のたびに `catalog_version` だけを調べ、他のプロセス (private の add_songs など) で
+/// 更新されていれば読み直す
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    pool: SqlitePool,
+    data: Arc<RwLock<Arc<CatalogData>>>,
+}
+
+impl Catalog {
+    pub async fn load(pool: SqlitePool) -> Result<Self> {
+        let data = CatalogData::load(&pool).await?;
+        Ok(Self {
+            pool,
+            data: Arc::new(RwLock::new(Arc::new(data))),
+        })
+    }
+
+    #[tracing::instrument(name = "catalog", skip_all)]
+    pub async fn current(&self) -> Result<Arc<CatalogData>> {
+        let cached = self.data.read().unwrap().clone();
+        if current_version(&self.pool).await? == cached.version {
+            return Ok(cached);
+        }
+        self.refresh().await
+    }
+
+    /// 読み直す
+    pub async fn refresh(&self) -> Result<Arc<CatalogData>> {
+        let data = Arc::new(CatalogData::load(&self.pool).await?);
+        *self.data.write().unwrap() = data.clone();
+        Ok(data)
+    }
+}
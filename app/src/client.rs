@@ -0,0 +1,505 @@
+use std::fmt;
+
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    api::{
+        AddGoalRequest, AddGoalResponse, AddSongsRequest, AddSongsResponse, AddUserRequest,
+        AddWebhookRequest, DeleteGoalRequest, DeleteWebhookRequest, DeliveriesRequest, DumpRequest,
+        DumpStatusRequest, GoalsRequest, ImportRequest, LeaderboardQuery, RecommendRequest,
+        RequestSongData, StatsQuery, StatsRequest, TimelineRequest, UpdateScoreRequest,
+        UpdateScoreResponse, UpdateSettingsRequest, UserDataRequest, WebhooksRequest,
+    },
+    dump::{DumpFile, PublishResult},
+    dump_job::DumpStatus,
+    export::ExportOptions,
+    goals::{GoalProgress, NewGoal},
+    import::ImportReport,
+    leaderboard::{ChartLeaderboard, LeaderboardKey, LevelSummary},
+    recommend::{RecommendFilter, Recommendations},
+    scores::RequestScoreData,
+    settings::{UserSettings, UserSettingsPatch},
+    stats::{GroupStats, StatsGroup},
+    timeline::{TimelineFilter, TimelinePoint},
+    webhook::{Delivery, NewWebhook, Webhook},
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// ベース URL として解釈できない
+    InvalidBaseUrl(String),
+    /// 認証が必要な API を `login` の前に呼んだ
+    NotLoggedIn,
+    /// 接続できない・応答を読めないなど
+    Http(reqwest::Error),
+    /// 403 (閲覧が許可されていない)
+    Forbidden(String),
+    /// 404 (ユーザーなどが存在しない)
+    NotFound(String),
+    /// 409 (既存のデータとの衝突)
+    Conflict(String),
+    /// その他のエラー応答 (本文はサーバーのメッセージ)
+    Status { status: StatusCode, message: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBaseUrl(url) => write!(f, "Invalid base url: {}", url),
+            Self::NotLoggedIn => write!(f, "Not logged in"),
+            Self::Http(e) => write!(f, "Request failed: {}", e),
+            Self::Forbidden(message) | Self::NotFound(message) | Self::Conflict(message) => {
+                write!(f, "{}", message)
+            }
+            Self::Status { status, message } => write!(f, "{}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Clone)]
+struct Credentials {
+    user: String,
+    password: String,
+}
+
+/// API のクライアント
+///
+/// サーバーにセッションは無く、認証が必要な API には毎回ユーザー名とパスワードを送る。
+/// private の API (`add_user`・`add_songs`) は private のベース URL を指すクライアントで呼ぶ
+/// (Lambda の private は IAM 認証なので、署名しないこのクライアントで呼べるのはローカルのサーバーだけ)
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    credentials: Option<Credentials>,
+}
+
+impl Client {
+    /// `base_url` は `https://example.com/` のように API のパスの手前まで
+    pub fn new(base_url: &str) -> ClientResult<Self> {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// タイムアウトなどを設定した `reqwest::Client` を使う
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> ClientResult<Self> {
+        let base_url =
+            Url::parse(base_url).map_err(|_| ClientError::InvalidBaseUrl(base_url.to_owned()))?;
+        if base_url.cannot_be_a_base() {
+            return Err(ClientError::InvalidBaseUrl(base_url.to_string()));
+        }
+        Ok(Self {
+            http,
+            base_url,
+            credentials: None,
+        })
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// 以降の呼び出しで使うユーザー名とパスワードを確かめて覚える
+    ///
+    /// 確認には副作用の無い `dump_status` を使う
+    pub async fn login(&mut self, user: &str, password: &str) -> ClientResult<()> {
+        let credentials = Credentials {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        };
+        self.post::<_, DumpStatus>(
+            &["api", "dump_status"],
+            &DumpStatusRequest {
+                user: credentials.user.clone(),
+                password: credentials.password.clone(),
+            },
+        )
+        .await?;
+        self.credentials = Some(credentials);
+        Ok(())
+    }
+
+    pub fn logout(&mut self) {
+        self.credentials = None;
+    }
+
+    /// ログイン中のユーザー名
+    pub fn user(&self) -> Option<&str> {
+        self.credentials.as_ref().map(|c| c.user.as_str())
+    }
+
+    fn credentials(&self) -> ClientResult<Credentials> {
+        self.credentials.clone().ok_or(ClientError::NotLoggedIn)
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("checked in with_http_client")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// 2xx 以外をエラーにする
+    async fn send(&self, request: RequestBuilder) -> ClientResult<Response> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let message = response.text().await.unwrap_or_default();
+        Err(match status {
+            StatusCode::FORBIDDEN => ClientError::Forbidden(message),
+            StatusCode::NOT_FOUND => ClientError::NotFound(message),
+            StatusCode::CONFLICT => ClientError::Conflict(message),
+            _ => ClientError::Status { status, message },
+        })
+    }
+
+    async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        body: &B,
+    ) -> ClientResult<T> {
+        let request = self.http.post(self.url(segments)).json(body);
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// 本文の無い応答
+    async fn post_unit<B: Serialize>(&self, segments: &[&str], body: &B) -> ClientResult<()> {
+        self.send(self.http.post(self.url(segments)).json(body))
+            .await?;
+        Ok(())
+    }
+
+    async fn post_bytes<B: Serialize>(&self, segments: &[&str], body: &B) -> ClientResult<Vec<u8>> {
+        let request = self.http.post(self.url(segments)).json(body);
+        Ok(self.send(request).await?.bytes().await?.to_vec())
+    }
+
+    async fn get<Q: Serialize, T: DeserializeOwned>(
+        &self,
+        segments: &[&str],
+        query: &Q,
+    ) -> ClientResult<T> {
+        let request = self.http.get(self.url(segments)).query(query);
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    pub async fn health(&self) -> ClientResult<()> {
+        self.send(self.http.get(self.url(&["api", "health"])))
+            .await?;
+        Ok(())
+    }
+
+    /// `dump_options` を渡すと、更新があったときに公開ダンプを作り直させる
+    pub async fn update_score(
+        &self,
+        scores: Vec<RequestScoreData>,
+        dump_options: Option<ExportOptions>,
+    ) -> ClientResult<UpdateScoreResponse> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "update_score"],
+            &UpdateScoreRequest {
+                user,
+                password,
+                scores,
+                dump: dump_options.is_some(),
+                dump_options: dump_options.unwrap_or_default(),
+            },
+        )
+        .await
+    }
+
+    pub async fn import(&self, format: &str, data: String) -> ClientResult<ImportReport> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "import"],
+            &ImportRequest {
+                user,
+                password,
+                format: format.to_owned(),
+                data,
+            },
+        )
+        .await
+    }
+
+    /// 非公開の設定なら `None`
+    pub async fn dump_user_data(
+        &self,
+        options: ExportOptions,
+    ) -> ClientResult<Option<PublishResult>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "dump_user_data"],
+            &DumpRequest {
+                user,
+                password,
+                options,
+            },
+        )
+        .await
+    }
+
+    pub async fn dump_status(&self) -> ClientResult<DumpStatus> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "dump_status"],
+            &DumpStatusRequest { user, password },
+        )
+        .await
+    }
+
+    /// `target` を省略すると自分のデータ
+    pub async fn user_data(
+        &self,
+        target: Option<&str>,
+        file: DumpFile,
+        options: ExportOptions,
+    ) -> ClientResult<Vec<u8>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post_bytes(
+            &["api", "user_data"],
+            &UserDataRequest {
+                user,
+                password,
+                target: target.map(str::to_owned),
+                file,
+                options,
+            },
+        )
+        .await
+    }
+
+    /// 共有トークンのダンプ (`file_name` は `bests.csv.gz` など)
+    pub async fn shared_data(&self, token: &str, file_name: &str) -> ClientResult<Vec<u8>> {
+        let request = self
+            .http
+            .get(self.url(&["api", "shared", token, file_name]));
+        Ok(self.send(request).await?.bytes().await?.to_vec())
+    }
+
+    pub async fn update_settings(&self, settings: UserSettingsPatch) -> ClientResult<UserSettings> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "update_settings"],
+            &UpdateSettingsRequest {
+                user,
+                password,
+                settings,
+            },
+        )
+        .await
+    }
+
+    pub async fn goals(&self) -> ClientResult<Vec<GoalProgress>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(&["api", "goals"], &GoalsRequest { user, password })
+            .await
+    }
+
+    /// 追加した目標の ID
+    pub async fn add_goal(&self, goal: NewGoal) -> ClientResult<i64> {
+        let Credentials { user, password } = self.credentials()?;
+        let res: AddGoalResponse = self
+            .post(
+                &["api", "goals", "add"],
+                &AddGoalRequest {
+                    user,
+                    password,
+                    goal,
+                },
+            )
+            .await?;
+        Ok(res.id)
+    }
+
+    pub async fn delete_goal(&self, id: i64) -> ClientResult<()> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post_unit(
+            &["api", "goals", "delete"],
+            &DeleteGoalRequest { user, password, id },
+        )
+        .await
+    }
+
+    pub async fn webhooks(&self) -> ClientResult<Vec<Webhook>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(&["api", "webhooks"], &WebhooksRequest { user, password })
+            .await
+    }
+
+    /// 署名の鍵は追加したときの応答にしか含まれない
+    pub async fn add_webhook(&self, url: &str) -> ClientResult<NewWebhook> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "webhooks", "add"],
+            &AddWebhookRequest {
+                user,
+                password,
+                url: url.to_owned(),
+            },
+        )
+        .await
+    }
+
+    pub async fn delete_webhook(&self, id: i64) -> ClientResult<()> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post_unit(
+            &["api", "webhooks", "delete"],
+            &DeleteWebhookRequest { user, password, id },
+        )
+        .await
+    }
+
+    /// `id` を省略するとすべての Webhook の送信履歴
+    pub async fn webhook_deliveries(
+        &self,
+        id: Option<i64>,
+        limit: i64,
+    ) -> ClientResult<Vec<Delivery>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "webhooks", "deliveries"],
+            &DeliveriesRequest {
+                user,
+                password,
+                id,
+                limit,
+            },
+        )
+        .await
+    }
+
+    pub async fn recommend(&self, filter: RecommendFilter) -> ClientResult<Recommendations> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "recommend"],
+            &RecommendRequest {
+                user,
+                password,
+                filter,
+            },
+        )
+        .await
+    }
+
+    /// `target` を省略すると自分の統計
+    pub async fn stats(
+        &self,
+        target: Option<&str>,
+        group_by: StatsGroup,
+    ) -> ClientResult<Vec<GroupStats>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "stats"],
+            &StatsRequest {
+                user,
+                password,
+                target: target.map(str::to_owned),
+                group_by,
+            },
+        )
+        .await
+    }
+
+    /// 公開しているユーザーの統計 (ログイン不要)
+    pub async fn public_stats(
+        &self,
+        user: &str,
+        group_by: StatsGroup,
+    ) -> ClientResult<Vec<GroupStats>> {
+        self.get(&["api", "stats", user], &StatsQuery { group_by })
+            .await
+    }
+
+    /// `target` を省略すると自分の推移
+    pub async fn timeline(
+        &self,
+        target: Option<&str>,
+        filter: TimelineFilter,
+    ) -> ClientResult<Vec<TimelinePoint>> {
+        let Credentials { user, password } = self.credentials()?;
+        self.post(
+            &["api", "timeline"],
+            &TimelineRequest {
+                user,
+                password,
+                target: target.map(str::to_owned),
+                filter,
+            },
+        )
+        .await
+    }
+
+    /// 公開しているユーザーの推移 (ログイン不要)
+    pub async fn public_timeline(
+        &self,
+        user: &str,
+        filter: &TimelineFilter,
+    ) -> ClientResult<Vec<TimelinePoint>> {
+        self.get(&["api", "timeline", user], filter).await
+    }
+
+    pub async fn chart_leaderboard(
+        &self,
+        chart_id: i64,
+        by: LeaderboardKey,
+    ) -> ClientResult<ChartLeaderboard> {
+        self.get(
+            &["api", "leaderboard", "chart", &chart_id.to_string()],
+            &LeaderboardQuery { by },
+        )
+        .await
+    }
+
+    pub async fn level_leaderboard(
+        &self,
+        level: i64,
+        by: LeaderboardKey,
+    ) -> ClientResult<LevelSummary> {
+        self.get(
+            &["api", "leaderboard", "level", &level.to_string()],
+            &LeaderboardQuery { by },
+        )
+        .await
+    }
+
+    /// private: ユーザーを追加する (同名のユーザーがいれば `Conflict`)
+    pub async fn add_user(&self, user: &str, password: &str) -> ClientResult<()> {
+        self.post_unit(
+            &["api", "private", "add_user"],
+            &AddUserRequest {
+                user: user.to_owned(),
+                password: password.to_owned(),
+            },
+        )
+        .await
+    }
+
+    /// private: 楽曲・譜面を追加・更新する
+    pub async fn add_songs(&self, songs: Vec<RequestSongData>) -> ClientResult<AddSongsResponse> {
+        self.post(&["api", "private", "add_songs"], &AddSongsRequest { songs })
+            .await
+    }
+}
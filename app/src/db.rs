@@ -0,0 +1,306 @@
+use std::{path::Path, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    Connection, SqliteConnection, SqlitePool,
+};
+
+/// バイナリに埋め込んだマイグレーション
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// ジャーナルモードの指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    /// ネットワークファイルシステム上なら `Delete`、それ以外は `Wal`
+    Auto,
+    /// WAL と読み取り専用のプール
+    Wal,
+    /// ロールバックジャーナルと 1 接続だけのプール (EFS 用)
+    Delete,
+}
+
+impl FromStr for JournalMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "wal" => Ok(Self::Wal),
+            "delete" => Ok(Self::Delete),
+            _ => Err(anyhow!("Unknown journal mode {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub url: String,
+    pub journal_mode: JournalMode,
+    /// 読み取り用プールの接続数 (WAL のときのみ)
+    pub read_connections: u32,
+    pub busy_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl DbConfig {
+    /// `DATABASE_URL`・`DB_JOURNAL_MODE`・`DB_READ_CONNECTIONS`・`DB_BUSY_TIMEOUT_MS` から作る
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            url: std::env::var("DATABASE_URL").unwrap_or("sqlite:./.db/ddr_score.db".to_string()),
+            journal_mode: match std::env::var("DB_JOURNAL_MODE") {
+                Ok(v) => v.parse()?,
+                Err(_) => JournalMode::Auto,
+            },
+            read_connections: match std::env::var("DB_READ_CONNECTIONS") {
+                Ok(v) => v.parse()?,
+                Err(_) => 4,
+            },
+            busy_timeout: match std::env::var("DB_BUSY_TIMEOUT_MS") {
+                Ok(v) => Duration::from_millis(v.parse()?),
+                Err(_) => Duration::from_secs(5),
+            },
+            idle_timeout: None,
+        })
+    }
+}
+
+/// 読み取り用と書き込み用のプール
+///
+/// 書き込みは 1 接続に絞り、SQLite のロック待ちではなくプールの順番待ちにする。
+/// `Delete` モードでは両方とも同じ 1 接続のプールを指す
+#[derive(Debug, Clone)]
+pub struct Db {
+    pub reader: SqlitePool,
+    pub writer: SqlitePool,
+    pub journal_mode: JournalMode,
+}
+
+/// `path` があるファイルシステムの種類 (/proc/mounts で最も長く一致するもの)
+fn filesystem_type(path: &Path) -> Option<String> {
+    let path = path
+        .parent()
+        .and_then(|p| std::fs::canonicalize(p).ok())
+        .unwrap_or_else(|| path.to_path_buf());
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            let mount_point = fields.next()?;
+            let fs_type = fields.next()?;
+            path.starts_with(mount_point)
+                .then(|| (mount_point.len(), fs_type.to_owned()))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, fs_type)| fs_type)
+}
+
+/// 起動時の確認
+///
+/// WAL は共有メモリ (`-shm` ファイルの mmap) でプロセス間の読み書きを調整するため、
+/// 複数のホストから同じファイルを開く NFS (EFS) 上では使えない。
+/// Lambda では public と private が別のホストから EFS 上の DB を開くので、
+/// ロールバックジャーナルと 1 接続のプールで動かす (NFS のロックは遅く、ロック待ちが起きやすい)
+///
+/// このとき読み取りも書き込みも同じ 1 接続を順番に使う。接続を長く持つ処理 (ダンプの生成など) の間は
+/// `update_score` を含む他のリクエストが待たされるので、そうした処理は接続を早く返すように作ること
+/// (ダウンロードはレスポンスを送る前にすべて生成する)。ロールバックジャーナルでは読み取り中の接続が
+/// 書き込みのコミットを止めるため、読み取り用に接続を分けても待ちは無くならない
+fn resolve_journal_mode(config: &DbConfig, path: &Path) -> JournalMode {
+    let fs_type = filesystem_type(path);
+    let network = fs_type
+        .as_deref()
+        .is_some_and(|t| t.starts_with("nfs") || t == "efs" || t == "cifs");
+    match config.journal_mode {
+        JournalMode::Auto if network => {
+            tracing::info!(
+                fs_type = fs_type.as_deref(),
+                "database is on a network filesystem, using rollback journal with a single connection"
+            );
+            JournalMode::Delete
+        }
+        JournalMode::Auto => JournalMode::Wal,
+        JournalMode::Wal if network => {
+            tracing::warn!(
+                fs_type = fs_type.as_deref(),
+                "WAL on a network filesystem is only safe when every process runs on the same host"
+            );
+            JournalMode::Wal
+        }
+        mode => mode,
+    }
+}
+
+pub async fn connect(config: &DbConfig) -> Result<Db> {
+    let options = SqliteConnectOptions::from_str(&config.url)?.busy_timeout(config.busy_timeout);
+    let journal_mode = resolve_journal_mode(config, options.get_filename());
+
+    let pool_options = |max_connections: u32| {
+        SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .acquire_timeout(Duration::from_secs(10))
+            .idle_timeout(config.idle_timeout)
+    };
+
+    match journal_mode {
+        JournalMode::Wal => {
+            // 先に書き込み側で WAL に切り替えておく (読み取り専用の接続では切り替えられない)
+            let writer = pool_options(1)
+                .connect_with(options.clone().journal_mode(SqliteJournalMode::Wal))
+                .await?;
+            let reader = pool_options(config.read_connections.max(1))
+                .connect_with(options.read_only(true))
+                .await?;
+            Ok(Db {
+                reader,
+                writer,
+                journal_mode,
+            })
+        }
+        _ => {
+            let pool = pool_options(1)
+                .connect_with(options.journal_mode(SqliteJournalMode::Delete))
+                .await?;
+            Ok(Db {
+                reader: pool.clone(),
+                writer: pool,
+                journal_mode: JournalMode::Delete,
+            })
+        }
+    }
+}
+
+/// DB が無ければ作り、未適用のマイグレーションを適用する (ローカル開発用)
+pub async fn migrate(url: &str) -> Result<()> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    if let Some(dir) = options.get_filename().parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    MIGRATOR.run(&mut conn).await?;
+    conn.close().await?;
+    Ok(())
+}
+
+/// マイグレーションを適用したメモリ上の DB (テスト用)
+#[cfg(test)]
+pub(crate) async fn test_pool() -> SqlitePool {
+    // メモリ上の DB は接続ごとに別なので 1 接続にする
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONSTRAINTS: i64 = 20261018000007;
+
+    /// `before` より前 (`before` 自身は含めない) のマイグレーションを順に適用する
+    async fn migrate_until(conn: &mut SqliteConnection, before: i64) {
+        for m in MIGRATOR.iter().filter(|m| m.version < before) {
+            sqlx::raw_sql(&m.sql).execute(&mut *conn).await.unwrap();
+        }
+    }
+
+    async fn apply(conn: &mut SqliteConnection, version: i64) {
+        let m = MIGRATOR.iter().find(|m| m.version == version).unwrap();
+        sqlx::raw_sql(&m.sql).execute(&mut *conn).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn constraints_migration_merges_duplicates() {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        migrate_until(&mut conn, CONSTRAINTS).await;
+
+        sqlx::raw_sql(
+            r"
+            insert into user (id, name, password_hash) values
+                (1, 'alice', ''), (2, 'alice', ''), (3, 'bob', '');
+            insert into song (id, name, ver) values (1, 'PARANOiA', '1st');
+            -- 2 は 1 の重複、3 は存在しない楽曲の譜面
+            insert into chart (id, song, play_type, difficulty, level) values
+                (1, 1, 0, 3, 14), (2, 1, 0, 3, 14), (3, 99, 0, 3, 14), (4, 1, 0, 4, 16);
+            insert into score (id, user, chart, score, created_at) values
+                (1, 1, 1, 900000, '2024-01-01T00:00:00Z'),
+                (2, 1, 2, 950000, '2024-01-02T00:00:00Z'),
+                (3, 9, 1, 800000, '2024-01-03T00:00:00Z'),
+                (4, 1, 3, 800000, '2024-01-04T00:00:00Z'),
+                (5, 3, 4, 700000, '2024-01-05T00:00:00Z');
+            insert into best (id, user, chart, score) values
+                (1, 1, 1, 1), (2, 1, 2, 2), (3, 1, 1, 999), (4, 3, 4, 5);
+            insert into goal (id, user, target, value, chart, created_at) values
+                (1, 1, 'score', '990000', 2, '');
+            ",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+        apply(&mut conn, CONSTRAINTS).await;
+
+        let users = sqlx::query_as::<_, (i64, String)>("select id, name from user order by id")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            users,
+            vec![
+                (1, "alice".to_owned()),
+                (2, "alice#2".to_owned()),
+                (3, "bob".to_owned())
+            ]
+        );
+
+        let charts = sqlx::query_scalar::<_, i64>("select id from chart order by id")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(charts, vec![1, 4]);
+
+        // 重複した譜面の記録は残す方に付け替え、参照先のない記録は消す
+        let scores =
+            sqlx::query_as::<_, (i64, i64, i64)>("select id, user, chart from score order by id")
+                .fetch_all(&mut conn)
+                .await
+                .unwrap();
+        assert_eq!(scores, vec![(1, 1, 1), (2, 1, 1), (5, 3, 4)]);
+
+        // 同じ譜面の自己ベストは新しい記録を指す 1 行だけ
+        let bests = sqlx::query_as::<_, (i64, i64, i64)>(
+            "select user, chart, score from best order by user, chart",
+        )
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(bests, vec![(1, 1, 2), (3, 4, 5)]);
+
+        let goal_chart = sqlx::query_scalar::<_, i64>("select chart from goal where id = 1")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(goal_chart, 1);
+
+        // 以降は一意制約で重複を防ぐ
+        assert!(
+            sqlx::query("insert into user (name, password_hash) values ('bob', '')")
+                .execute(&mut conn)
+                .await
+                .is_err()
+        );
+        assert!(sqlx::query(
+            "insert into chart (song, play_type, difficulty, level) values (1, 0, 3, 14)"
+        )
+        .execute(&mut conn)
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+use crate::{
+    catalog::CatalogData,
+    export::{Cell, ExportCompression, ExportFormat, ExportOptions, SharedBuffer, TableWriter},
+    storage::{Storage, Upload},
+};
+
+/// この大きさ以上溜まったら圧縮済みの出力を渡す
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// ダンプのカラム構成などを変えたら上げる
+pub const SCHEMA_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DumpFile {
+    #[serde(alias = "bests.tsv.gz")]
+    Bests,
+    #[serde(alias = "scores.tsv.gz")]
+    Scores,
+}
+
+const BESTS_COLUMNS: [&str; 10] = [
+    "id",
+    "title",
+    "difficulty",
+    "level",
+    "score",
+    "clear_rank",
+    "clear_kind",
+    "flare_rank",
+    "flare_skill",
+    // フロントエンドは位置で読むので、後から足したカラムは末尾に置く
+    "ex_score",
+];
+
+const SCORES_COLUMNS: [&str; 11] = [
+    "id",
+    "title",
+    "difficulty",
+    "level",
+    "score",
+    "clear_rank",
+    "clear_kind",
+    "flare_rank",
+    "flare_skill",
+    "updated_at",
+    "ex_score",
+];
+
+impl DumpFile {
+    pub const ALL: [DumpFile; 2] = [DumpFile::Bests, DumpFile::Scores];
+
+    pub fn stem(&self) -> &'static str {
+        match self {
+            Self::Bests => "bests",
+            Self::Scores => "scores",
+        }
+    }
+
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            Self::Bests => &BESTS_COLUMNS,
+            Self::Scores => &SCORES_COLUMNS,
+        }
+    }
+
+    /// `bests.tsv.gz` のようなファイル名から種類と形式を得る
+    pub fn parse_file_name(name: &str) -> Result<(DumpFile, ExportOptions)> {
+        let (stem, options) = ExportOptions::parse_file_name(name)?;
+        let file = Self::ALL
+            .into_iter()
+            .find(|f| f.stem() == stem)
+            .ok_or_else(|| anyhow!("Unknown dump file: {}", name))?;
+        Ok((file, options))
+    }
+
+    /// 公開ダンプの S3 キー
+    pub fn public_key(&self, user: &str, options: &ExportOptions) -> String {
+        data_key(user, &options.file_name(self.stem()))
+    }
+}
+
+/// 公開ダンプの置き場所にあるファイルの S3 キー
+fn data_key(user: &str, name: &str) -> String {
+    format!("scores/{}/data/{}", user, name)
+}
+
+/// 公開ダンプの manifest の S3 キー
+pub fn manifest_key(user: &str) -> String {
+    data_key(user, "manifest.json")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ManifestEntry {
+    pub rows: usize,
+    pub size: usize,
+    pub sha256: String,
+    pub generated_at: DateTime<Utc>,
+}
+
+/// 公開ダンプと同じ場所に置く、各ファイルの内容の要約
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DumpManifest {
+    pub schema_version: u32,
+    /// いずれかのファイルが最後に更新された日時
+    pub generated_at: DateTime<Utc>,
+    /// ファイル名ごと (`bests.tsv.gz` など)
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PublishResult {
+    pub manifest: DumpManifest,
+    /// 内容が変わったためアップロードしたファイル
+    pub uploaded: Vec<String>,
+}
+
+async fn load_manifest(storage: &Storage, user: &str) -> Result<Option<DumpManifest>> {
+    let Some(raw) = storage.get(&manifest_key(user)).await? else {
+        return Ok(None);
+    };
+    // 読めないものや形式の古いものは無かったことにして作り直す
+    Ok(serde_json::from_slice::<DumpManifest>(&raw)
+        .ok()
+        .filter(|m| m.schema_version == SCHEMA_VERSION))
+}
+
+/// 生成したダンプの要約
+#[derive(Debug, Clone)]
+pub struct DumpSummary {
+    pub rows: usize,
+    pub size: usize,
+    pub sha256: String,
+}
+
+/// 公開ダンプの形式として使えるか
+///
+/// 公開ダンプのファイル名と manifest はカラムを区別せず、フロントエンドは全カラムを位置で読むため、
+/// カラムの絞り込み (`columns`) は `user_data` のダウンロードだけで受け付ける
+pub fn check_publish_options(options: &ExportOptions) -> Result<()> {
+    if options.columns.is_some() {
+        return Err(anyhow!(
+            "Published dumps always contain every column, columns can only be selected for user_data"
+        ));
+    }
+    Ok(())
+}
+
+/// 公開ダンプを作り、前回から内容が変わったファイルと manifest だけをアップロードする
+///
+/// 変更の有無はハッシュだけを計算する 1 回目の生成で調べ、変わっていれば生成し直しながらアップロードする
+#[tracing::instrument(skip_all, fields(user = user))]
+pub async fn publish(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    storage: &Storage,
+    user: &str,
+    user_id: i64,
+    options: &ExportOptions,
+) -> Result<PublishResult> {
+    check_publish_options(options)?;
+    let prev = load_manifest(storage, user).await?;
+    let now = Utc::now();
+    let mut files = prev.as_ref().map(|m| m.files.clone()).unwrap_or_default();
+
+    let mut uploaded = vec![];
+    for file in DumpFile::ALL {
+        let name = options.file_name(file.stem());
+        let summary = generate(pool, catalog, user_id, file, options, &mut Discard).await?;
+        if files.get(&name).is_some_and(|e| e.sha256 == summary.sha256) {
+            continue;
+        }
+
+        let mut upload = storage
+            .upload(&file.public_key(user, options), options.content_type())
+            .await?;
+        let res = generate(pool, catalog, user_id, file, options, &mut upload).await;
+        // 2 回の生成の間に更新があってもアップロードした内容で記録する
+        let summary = match res {
+            Ok(summary) => {
+                upload.finish().await?;
+                summary
+            }
+            Err(e) => {
+                upload.abort().await?;
+                return Err(e);
+            }
+        };
+
+        files.insert(
+            name.clone(),
+            ManifestEntry {
+                rows: summary.rows,
+                size: summary.size,
+                sha256: summary.sha256,
+                generated_at: now,
+            },
+        );
+        uploaded.push(name);
+    }
+
+    let manifest = match prev {
+        Some(prev) if uploaded.is_empty() => prev,
+        _ => {
+            let manifest = DumpManifest {
+                schema_version: SCHEMA_VERSION,
+                generated_at: now,
+                files,
+            };
+            storage
+                .put(
+                    &manifest_key(user),
+                    serde_json::to_vec_pretty(&manifest)?,
+                    "application/json",
+                )
+                .await?;
+            manifest
+        }
+    };
+
+    Ok(PublishResult { manifest, uploaded })
+}
+
+/// 公開ダンプと manifest を削除する
+///
+/// 以前に別の形式で公開したものも残さないよう、manifest に載っているファイル
+/// (形式の古い manifest も含む) とすべての形式・圧縮の組み合わせを削除する
+pub async fn unpublish(storage: &Storage, user: &str) -> Result<()> {
+    let mut keys = HashSet::new();
+    if let Some(raw) = storage.get(&manifest_key(user)).await? {
+        if let Ok(manifest) = serde_json::from_slice::<DumpManifest>(&raw) {
+            keys.extend(manifest.files.keys().map(|name| data_key(user, name)));
+        }
+    }
+    for file in DumpFile::ALL {
+        for format in ExportFormat::ALL {
+            for compression in ExportCompression::ALL {
+                let options = ExportOptions {
+                    format,
+                    compression,
+                    columns: None,
+                };
+                keys.insert(file.public_key(user, &options));
+            }
+        }
+    }
+
+    for key in keys {
+        storage.delete(&key).await?;
+    }
+    // 途中で失敗しても manifest から削除し直せるよう最後に消す
+    storage.delete(&manifest_key(user)).await
+}
+
+/// ダンプを生成しながら少しずつ返す (HTTP レスポンスの本文用)
+///
+/// 送り始めてからはエラーを返せないため、指定の誤りはここで検出する
+pub fn stream(
+    pool: SqlitePool,
+    catalog: Arc<CatalogData>,
+    user_id: i64,
+    file: DumpFile,
+    options: ExportOptions,
+) -> Result<impl Stream<Item = Result<Vec<u8>>>> {
+    options.select(file.columns())?;
+
+    // 受け取り側が遅ければ生成も待つので、溜まるのはこの数の chunk まで
+    let (mut tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let res = generate(&pool, &catalog, user_id, file, &options, &mut tx).await;
+        if let Err(e) = res {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    Ok(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|c| (c, rx))
+    }))
+}
+
+/// ダンプをすべて生成してから返す
+///
+/// 生成中は DB の接続を使い続けるため、接続が 1 つしかないとき (`JournalMode::Delete`) に
+/// 受け取り側の速さに合わせて生成すると、遅いダウンロードが他のリクエストを止めてしまう
+pub async fn buffer(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    file: DumpFile,
+    options: &ExportOptions,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    generate(pool, catalog, user_id, file, options, &mut buf).await?;
+    Ok(buf)
+}
+
+/// 生成したダンプの出力先
+pub trait ChunkSink: Send {
+    fn send_chunk(&mut self, chunk: Vec<u8>) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// 捨てる (ハッシュなどの計算だけ行う)
+pub struct Discard;
+
+impl ChunkSink for Discard {
+    async fn send_chunk(&mut self, _: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ChunkSink for Vec<u8> {
+    async fn send_chunk(&mut self, chunk: Vec<u8>) -> Result<()> {
+        self.extend_from_slice(&chunk);
+        Ok(())
+    }
+}
+
+impl ChunkSink for Upload {
+    async fn send_chunk(&mut self, chunk: Vec<u8>) -> Result<()> {
+        self.write(&chunk).await
+    }
+}
+
+impl ChunkSink for mpsc::Sender<Result<Vec<u8>>> {
+    async fn send_chunk(&mut self, chunk: Vec<u8>) -> Result<()> {
+        self.send(Ok(chunk))
+            .await
+            .map_err(|_| anyhow!("Dump receiver dropped"))
+    }
+}
+
+/// 圧縮済みの出力を `CHUNK_SIZE` ごとに渡しながら、大きさとハッシュを数える
+struct ChunkedWriter<'a, S> {
+    w: TableWriter<SharedBuffer>,
+    buf: SharedBuffer,
+    hasher: Sha256,
+    size: usize,
+    sink: &'a mut S,
+}
+
+impl<'a, S: ChunkSink> ChunkedWriter<'a, S> {
+    fn new(columns: &[&'static str], options: &ExportOptions, sink: &'a mut S) -> Result<Self> {
+        let buf = SharedBuffer::default();
+        Ok(Self {
+            w: TableWriter::new(buf.clone(), columns, options)?,
+            buf,
+            hasher: Sha256::new(),
+            size: 0,
+            sink,
+        })
+    }
+
+    async fn emit(
+        hasher: &mut Sha256,
+        size: &mut usize,
+        sink: &mut S,
+        chunk: Vec<u8>,
+    ) -> Result<()> {
+        hasher.update(&chunk);
+        *size += chunk.len();
+        sink.send_chunk(chunk).await
+    }
+
+    async fn write_row(&mut self, row: Vec<Cell>) -> Result<()> {
+        self.w.write_row(row)?;
+        if self.buf.len() >= CHUNK_SIZE {
+            let chunk = self.buf.take();
+            Self::emit(&mut self.hasher, &mut self.size, self.sink, chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<DumpSummary> {
+        let Self {
+            w,
+            buf,
+            mut hasher,
+            mut size,
+            sink,
+        } = self;
+        let rows = w.rows();
+        // 圧縮の残りもここで書き出される
+        w.finish()?;
+        let chunk = buf.take();
+        if !chunk.is_empty() {
+            Self::emit(&mut hasher, &mut size, sink, chunk).await?;
+        }
+        Ok(DumpSummary {
+            rows,
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// 行を SQLite から 1 行ずつ読み、圧縮済みの出力を `sink` に渡す
+///
+/// メモリに載るのは譜面の一覧と `CHUNK_SIZE` 程度の出力だけで、記録の量には依らない
+#[tracing::instrument(skip_all, fields(file = file.stem()))]
+pub async fn generate(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    file: DumpFile,
+    options: &ExportOptions,
+    sink: &mut impl ChunkSink,
+) -> Result<DumpSummary> {
+    let w = ChunkedWriter::new(file.columns(), options, sink)?;
+    match file {
+        DumpFile::Bests => generate_bests(pool, catalog, user_id, w).await,
+        DumpFile::Scores => generate_scores(pool, catalog, user_id, w).await,
+    }
+}
+
+async fn generate_bests(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    mut w: ChunkedWriter<'_, impl ChunkSink>,
+) -> Result<DumpSummary> {
+    let mut unlocked = HashSet::new();
+    let mut bests = sqlx::query!(
+        r"select
+            chart.id,
+            song.name as title,
+            chart.difficulty,
+            chart.level,
+            bs.score,
+            bs.ex_score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank,
+            bs.flare_skill
+        from
+            best
+        inner join score as bs on bs.id = best.score
+        inner join chart on chart.id = best.chart
+        inner join song on song.id = chart.song
+        where
+            best.user = ?
+        order by chart.id",
+        user_id
+    )
+    .fetch(pool);
+
+    while let Some(b) = bests.try_next().await? {
+        unlocked.insert(b.id);
+        w.write_row(vec![
+            b.id.into(),
+            b.title.into(),
+            b.difficulty.into(),
+            b.level.into(),
+            b.score.into(),
+            b.clear_rank.into(),
+            b.clear_kind.into(),
+            b.flare_rank.into(),
+            b.flare_skill.into(),
+            b.ex_score.into(),
+        ])
+        .await?;
+    }
+    drop(bests);
+
+    for c in catalog.charts() {
+        if !unlocked.contains(&c.id) {
+            w.write_row(vec![
+                c.id.into(),
+                c.title.as_str().into(),
+                c.difficulty.into(),
+                c.level.into(),
+                Cell::Null,
+                Cell::Null,
+                "LOCKED".into(),
+                Cell::Null,
+                Cell::Null,
+                Cell::Null,
+            ])
+            .await?;
+        }
+    }
+
+    w.finish().await
+}
+
+async fn generate_scores(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    mut w: ChunkedWriter<'_, impl ChunkSink>,
+) -> Result<DumpSummary> {
+    let mut scores = sqlx::query!(
+        r#"select
+            chart,
+            group_concat(ifnull(cast(score as text), '') order by played_at, id) as "score: String",
+            group_concat(ifnull(cast(ex_score as text), '') order by played_at, id) as "ex_score: String",
+            group_concat(ifnull(clear_rank, '') order by played_at, id) as "clear_rank: String",
+            group_concat(ifnull(clear_kind, '') order by played_at, id) as "clear_kind: String",
+            group_concat(ifnull(cast(flare_rank as text), '') order by played_at, id) as "flare_rank: String",
+            group_concat(ifnull(cast(flare_skill as text), '') order by played_at, id) as "flare_skill: String",
+            group_concat(created_at order by played_at, id) as "updated_at: String"
+        from
+            score
+        where
+            user = ?
+        group by chart
+        order by chart"#,
+        user_id
+    )
+    .fetch(pool);
+
+    while let Some(s) = scores.try_next().await? {
+        let Some(c) = catalog.chart(s.chart) else {
+            continue;
+        };
+        w.write_row(vec![
+            s.chart.into(),
+            c.title.as_str().into(),
+            c.difficulty.into(),
+            c.level.into(),
+            s.score.into(),
+            s.clear_rank.into(),
+            s.clear_kind.into(),
+            s.flare_rank.into(),
+            s.flare_skill.into(),
+            s.updated_at.into(),
+            s.ex_score.into(),
+        ])
+        .await?;
+    }
+    drop(scores);
+
+    w.finish().await
+}
@@ -0,0 +1,313 @@
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    catalog::Catalog,
+    db::Db,
+    dump::{self, PublishResult},
+    export::ExportOptions,
+    job_queue::{self, JobRunner},
+    settings::{self, Visibility},
+    storage::Storage,
+};
+
+/// 失敗時に試す回数 (初回を含む)
+const MAX_ATTEMPTS: i64 = 5;
+/// 再試行までの待ち時間の初期値 (試すたびに倍にする)
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct DumpJob {
+    pub user_id: i64,
+    pub user: String,
+    pub options: ExportOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DumpStatus {
+    /// 実行中または実行待ち (再試行待ちを含む) の依頼があるか
+    pub pending: bool,
+    pub requested_at: Option<String>,
+    pub succeeded_at: Option<String>,
+    pub failed_at: Option<String>,
+    /// 直近の依頼で試した回数
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+fn now() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// 公開設定に従ってダンプを置き (非公開なら消し)、結果を記録する
+///
+/// 生成は読み取り用のプールで行い、書き込み用の接続は結果の記録にだけ使う
+#[tracing::instrument(skip_all, fields(user = job.user, attempt = attempt))]
+pub async fn run(
+    db: &Db,
+    catalog: &Catalog,
+    storage: &Storage,
+    job: &DumpJob,
+    attempt: i64,
+) -> Result<Option<PublishResult>> {
+    let res: Result<_> = async {
+        let settings = settings::load_settings(&db.reader, job.user_id).await?;
+        if settings.visibility == Visibility::Public {
+            let catalog = catalog.current().await?;
+            let res = dump::publish(
+                &db.reader,
+                &catalog,
+                storage,
+                &job.user,
+                job.user_id,
+                &job.options,
+            )
+            .await?;
+            Ok(Some(res))
+        } else {
+            dump::unpublish(storage, &job.user).await?;
+            Ok(None)
+        }
+    }
+    .await;
+
+    let now = now();
+    match &res {
+        Ok(_) => {
+            sqlx::query!(
+                r"insert into dump_status (user, succeeded_at, attempts) values (?, ?, ?)
+                on conflict (user) do update set
+                    succeeded_at = excluded.succeeded_at,
+                    attempts = excluded.attempts,
+                    last_error = null",
+                job.user_id,
+                now,
+                attempt
+            )
+            .execute(&db.writer)
+            .await?;
+        }
+        Err(e) => {
+            let error = e.to_string();
+            sqlx::query!(
+                r"insert into dump_status (user, failed_at, attempts, last_error) values (?, ?, ?, ?)
+                on conflict (user) do update set
+                    failed_at = excluded.failed_at,
+                    attempts = excluded.attempts,
+                    last_error = excluded.last_error",
+                job.user_id,
+                now,
+                attempt,
+                error
+            )
+            .execute(&db.writer)
+            .await?;
+        }
+    }
+    res
+}
+
+/// 実行を始めた依頼
+struct Claimed {
+    job: DumpJob,
+    attempt: i64,
+    /// 取ったときに設定した `lease_until` (変わっていれば実行中に新しい依頼が来た)
+    lease_until: i64,
+}
+
+/// ダンプの作り直しの依頼を DB に残し、バックグラウンドで実行する
+///
+/// 同じユーザーの依頼は最新のものだけを残す。失敗したら間隔を空けて `MAX_ATTEMPTS` 回まで試す。
+/// 結果は `status` で確認する
+#[derive(Debug, Clone)]
+pub struct DumpQueue {
+    db: Db,
+    catalog: Catalog,
+    storage: Storage,
+    /// このプロセスで依頼を処理中か
+    draining: Arc<AtomicBool>,
+}
+
+impl DumpQueue {
+    pub fn new(db: Db, catalog: Catalog, storage: Storage) -> Self {
+        Self {
+            db,
+            catalog,
+            storage,
+            draining: Arc::default(),
+        }
+    }
+
+    pub async fn enqueue(&self, job: DumpJob) -> Result<()> {
+        let now = now();
+        let next_attempt_at = Utc::now().timestamp();
+        let options = serde_json::to_string(&job.options)?;
+        sqlx::query!(
+            r"insert into dump_status (user, requested_at, attempts, options, next_attempt_at)
+            values (?, ?, 0, ?, ?)
+            on conflict (user) do update set
+                requested_at = excluded.requested_at,
+                attempts = 0,
+                options = excluded.options,
+                next_attempt_at = excluded.next_attempt_at,
+                lease_until = null",
+            job.user_id,
+            now,
+            options,
+            next_attempt_at
+        )
+        .execute(&self.db.writer)
+        .await?;
+
+        self.resume();
+        Ok(())
+    }
+
+    /// 実行待ちの依頼があればバックグラウンドで処理する
+    pub fn resume(&self) {
+        job_queue::resume(self);
+    }
+
+    /// 時刻の来た依頼を 1 つ取る
+    async fn claim(&self) -> Result<Option<Claimed>> {
+        let now = Utc::now().timestamp();
+        let lease_until = job_queue::lease_until();
+        let Some(row) = sqlx::query!(
+            r#"update dump_status set
+                lease_until = ?1,
+                attempts = attempts + 1
+            where user = (
+                select user from dump_status
+                where next_attempt_at <= ?2 and (lease_until is null or lease_until <= ?2)
+                order by next_attempt_at limit 1
+            )
+            returning user, attempts, options as "options!""#,
+            lease_until,
+            now
+        )
+        .fetch_optional(&self.db.writer)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let user = sqlx::query!(r"select name from user where id = ?", row.user)
+            .fetch_one(&self.db.reader)
+            .await?
+            .name;
+        Ok(Some(Claimed {
+            job: DumpJob {
+                user_id: row.user,
+                user,
+                options: serde_json::from_str(&row.options)?,
+            },
+            attempt: row.attempts,
+            lease_until,
+        }))
+    }
+
+    async fn attempt(&self, claimed: Claimed) -> Result<()> {
+        let Claimed {
+            job,
+            attempt,
+            lease_until,
+        } = claimed;
+        let res = run(&self.db, &self.catalog, &self.storage, &job, attempt).await;
+
+        // 成功したか諦めたら依頼を消し、そうでなければ再試行の時刻を設定する
+        let next_attempt_at = match &res {
+            Ok(_) => None,
+            Err(e) if attempt >= MAX_ATTEMPTS => {
+                tracing::error!(
+                    user = job.user,
+                    "dump gave up after {} attempts: {:#}",
+                    attempt,
+                    e
+                );
+                None
+            }
+            Err(e) => {
+                tracing::warn!(user = job.user, attempt, "dump failed: {:#}", e);
+                Some(
+                    Utc::now().timestamp()
+                        + job_queue::retry_delay(RETRY_DELAY, attempt).as_secs() as i64,
+                )
+            }
+        };
+        // 実行中に新しい依頼が来ていればそちらを残す
+        sqlx::query!(
+            r"update dump_status set
+                next_attempt_at = ?1,
+                lease_until = null,
+                options = case when ?1 is null then null else options end
+            where user = ?2 and lease_until = ?3",
+            next_attempt_at,
+            job.user_id,
+            lease_until
+        )
+        .execute(&self.db.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn status(&self, user_id: i64) -> Result<DumpStatus> {
+        let row = sqlx::query!(
+            r"select requested_at, succeeded_at, failed_at, attempts, last_error, next_attempt_at
+            from dump_status where user = ?",
+            user_id
+        )
+        .fetch_optional(&self.db.reader)
+        .await?;
+
+        Ok(match row {
+            Some(r) => DumpStatus {
+                pending: r.next_attempt_at.is_some(),
+                requested_at: r.requested_at,
+                succeeded_at: r.succeeded_at,
+                failed_at: r.failed_at,
+                attempts: r.attempts,
+                last_error: r.last_error,
+            },
+            None => DumpStatus {
+                pending: false,
+                requested_at: None,
+                succeeded_at: None,
+                failed_at: None,
+                attempts: 0,
+                last_error: None,
+            },
+        })
+    }
+}
+
+impl JobRunner for DumpQueue {
+    fn draining(&self) -> &AtomicBool {
+        &self.draining
+    }
+
+    async fn next_due(&self) -> Result<Option<i64>> {
+        let now = Utc::now().timestamp();
+        Ok(sqlx::query_scalar!(
+            r"select min(next_attempt_at) from dump_status
+            where next_attempt_at is not null and (lease_until is null or lease_until <= ?)",
+            now
+        )
+        .fetch_one(&self.db.reader)
+        .await?)
+    }
+
+    async fn run_next(&self) -> Result<bool> {
+        let Some(claimed) = self.claim().await? else {
+            return Ok(false);
+        };
+        self.attempt(claimed).await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Tsv,
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [Self::Tsv, Self::Csv, Self::Jsonl];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Tsv => "tsv",
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Tsv => "text/tab-separated-values",
+            Self::Csv => "text/csv",
+            Self::Jsonl => "application/jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportCompression {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl ExportCompression {
+    pub const ALL: [ExportCompression; 3] = [Self::None, Self::Gzip, Self::Zstd];
+
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("application/gzip"),
+            Self::Zstd => Some("application/zstd"),
+        }
+    }
+}
+
+/// 出力形式の指定 (既定は gzip 圧縮した TSV、全カラム)
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct ExportOptions {
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(default)]
+    pub compression: ExportCompression,
+    pub columns: Option<Vec<String>>,
+}
+
+impl ExportOptions {
+    /// `{stem}.{format}[.{compression}]`
+    pub fn file_name(&self, stem: &str) -> String {
+        match self.compression.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, self.format.extension(), ext),
+            None => format!("{}.{}", stem, self.format.extension()),
+        }
+    }
+
+    /// `file_name` の逆 (カラム指定は含まない)
+    pub fn parse_file_name(name: &str) -> Result<(&str, ExportOptions)> {
+        let mut parts = name.split('.');
+        let stem = parts.next().unwrap_or_default();
+        let format = match parts.next() {
+            Some("tsv") => ExportFormat::Tsv,
+            Some("csv") => ExportFormat::Csv,
+            Some("jsonl") => ExportFormat::Jsonl,
+            _ => return Err(anyhow!("Unknown file format: {}", name)),
+        };
+        let compression = match parts.next() {
+            None => ExportCompression::None,
+            Some("gz") => ExportCompression::Gzip,
+            Some("zst") => ExportCompression::Zstd,
+            _ => return Err(anyhow!("Unknown file compression: {}", name)),
+        };
+        if parts.next().is_some() {
+            return Err(anyhow!("Unknown file name: {}", name));
+        }
+
+        Ok((
+            stem,
+            ExportOptions {
+                format,
+                compression,
+                columns: None,
+            },
+        ))
+    }
+
+    /// `columns` のうち出力するものの位置
+    pub fn select(&self, columns: &[&str]) -> Result<Vec<usize>> {
+        match &self.columns {
+            Some(cs) => cs
+                .iter()
+                .map(|c| {
+                    columns
+                        .iter()
+                        .position(|n| n == c)
+                        .ok_or_else(|| anyhow!("Unknown column: {}", c))
+                })
+                .collect(),
+            None => Ok((0..columns.len()).collect()),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.compression
+            .content_type()
+            .unwrap_or(self.format.content_type())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Cell {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+impl From<i64> for Cell {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<String> for Cell {
+    fn from(v: String) -> Self {
+        Self::Text(v)
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(v: &str) -> Self {
+        Self::Text(v.to_owned())
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Self::Null, Into::into)
+    }
+}
+
+impl Cell {
+    fn to_field(&self) -> String {
+        match self {
+            Self::Null => "".to_owned(),
+            Self::Int(v) => v.to_string(),
+            Self::Text(v) => v.clone(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Null => serde_json::Value::Null,
+            Self::Int(v) => (*v).into(),
+            Self::Text(v) => v.as_str().into(),
+        }
+    }
+}
+
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(w: W, compression: ExportCompression) -> io::Result<Self> {
+        Ok(match compression {
+            ExportCompression::None => Self::None(w),
+            ExportCompression::Gzip => Self::Gzip(GzEncoder::new(w, Compression::new(6))),
+            ExportCompression::Zstd => Self::Zstd(zstd::Encoder::new(w, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(w) => Ok(w),
+            Self::Gzip(e) => e.finish(),
+            Self::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(w) => w.write(buf),
+            Self::Gzip(e) => e.write(buf),
+            Self::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(w) => w.flush(),
+            Self::Gzip(e) => e.flush(),
+            Self::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// `Encoder` で圧縮されたデータを展開する
+pub fn decompress(raw: &[u8], compression: ExportCompression) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    match compression {
+        ExportCompression::None => buf.extend_from_slice(raw),
+        ExportCompression::Gzip => {
+            GzDecoder::new(raw).read_to_end(&mut buf)?;
+        }
+        ExportCompression::Zstd => {
+            zstd::Decoder::new(raw)?.read_to_end(&mut buf)?;
+        }
+    }
+    Ok(buf)
+}
+
+/// 書き込まれた分を別のハンドルから少しずつ取り出せるバッファ
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ここまでに書き込まれた分を取り出す
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Sink<W: Write> {
+    Delimited(Box<csv::Writer<Encoder<W>>>),
+    Jsonl(Encoder<W>),
+}
+
+/// 表形式のデータを指定の形式・圧縮・カラムで書き出す
+pub struct TableWriter<W: Write> {
+    sink: Sink<W>,
+    names: Vec<&'static str>,
+    selected: Vec<usize>,
+    rows: usize,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(w: W, columns: &[&'static str], options: &ExportOptions) -> Result<Self> {
+        let selected = options.select(columns)?;
+
+        let enc = Encoder::new(w, options.compression)?;
+        let sink = match options.format {
+            ExportFormat::Tsv | ExportFormat::Csv => {
+                let mut w = csv::WriterBuilder::new()
+                    .delimiter(if options.format == ExportFormat::Tsv {
+                        b'\t'
+                    } else {
+                        b','
+                    })
+                    .from_writer(enc);
+                w.write_record(selected.iter().map(|&i| columns[i]))?;
+                Sink::Delimited(Box::new(w))
+            }
+            ExportFormat::Jsonl => Sink::Jsonl(enc),
+        };
+
+        Ok(Self {
+            sink,
+            names: columns.to_vec(),
+            selected,
+            rows: 0,
+        })
+    }
+
+    pub fn write_row(&mut self, row: Vec<Cell>) -> Result<()> {
+        match &mut self.sink {
+            Sink::Delimited(w) => {
+                w.write_record(self.selected.iter().map(|&i| row[i].to_field()))?;
+            }
+            Sink::Jsonl(w) => {
+                let obj = self
+                    .selected
+                    .iter()
+                    .map(|&i| (self.names[i].to_owned(), row[i].to_json()))
+                    .collect::<serde_json::Map<_, _>>();
+                serde_json::to_writer(&mut *w, &obj)?;
+                w.write_all(b"\n")?;
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// ヘッダを除いた書き出し済みの行数
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn finish(self) -> Result<W> {
+        let enc = match self.sink {
+            Sink::Delimited(w) => w.into_inner().map_err(|e| anyhow!("{}", e))?,
+            Sink::Jsonl(w) => w,
+        };
+        Ok(enc.finish()?)
+    }
+}
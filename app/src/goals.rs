@@ -0,0 +1,508 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{
+    scores::{Improvement, ScoreValues},
+    ClearKind, ClearRank, Difficulty, NotFound,
+};
+
+/// 目標の達成条件 (各譜面の自己ベストがこれ以上であれば達成)
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "target", content = "value", rename_all = "snake_case")]
+pub enum GoalTarget {
+    Score(i64),
+    ClearRank(String),
+    ClearKind(String),
+    FlareRank(i64),
+}
+
+impl GoalTarget {
+    fn from_row(target: &str, value: &str) -> Result<Self> {
+        match target {
+            "score" => Ok(Self::Score(value.parse()?)),
+            "clear_rank" => Ok(Self::ClearRank(value.to_owned())),
+            "clear_kind" => Ok(Self::ClearKind(value.to_owned())),
+            "flare_rank" => Ok(Self::FlareRank(value.parse()?)),
+            _ => Err(anyhow!("Unknown goal target {}", target)),
+        }
+    }
+
+    fn to_row(&self) -> (&'static str, String) {
+        match self {
+            Self::Score(s) => ("score", s.to_string()),
+            Self::ClearRank(r) => ("clear_rank", r.to_ascii_uppercase()),
+            Self::ClearKind(k) => ("clear_kind", k.to_ascii_uppercase()),
+            Self::FlareRank(f) => ("flare_rank", f.to_string()),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Self::ClearRank(r) => r.parse::<ClearRank>().map(|_| ()),
+            Self::ClearKind(k) => k.parse::<ClearKind>().map(|_| ()),
+            Self::Score(_) | Self::FlareRank(_) => Ok(()),
+        }
+    }
+
+    fn is_met(&self, best: &ScoreValues) -> bool {
+        match self {
+            Self::Score(s) => best.score.is_some_and(|b| b >= *s),
+            Self::ClearRank(r) => {
+                let target = r.parse::<ClearRank>().map(|r| r as i64);
+                let cur = best
+                    .clear_rank
+                    .as_ref()
+                    .and_then(|r| r.parse::<ClearRank>().ok())
+                    .map(|r| r as i64);
+                matches!((cur, target), (Some(c), Ok(t)) if c <= t)
+            }
+            Self::ClearKind(k) => {
+                let target = k.parse::<ClearKind>().map(|k| k as i64);
+                let cur = best
+                    .clear_kind
+                    .as_ref()
+                    .and_then(|k| k.parse::<ClearKind>().ok())
+                    .map(|k| k as i64);
+                matches!((cur, target), (Some(c), Ok(t)) if c <= t)
+            }
+            Self::FlareRank(f) => best.flare_rank.is_some_and(|b| b >= *f),
+        }
+    }
+}
+
+/// 目標の対象となる譜面の範囲 (指定のないものは絞り込まない)
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct GoalScope {
+    pub level: Option<i64>,
+    pub difficulty: Option<i64>,
+    pub chart: Option<i64>,
+}
+
+impl GoalScope {
+    fn contains(&self, chart: i64, level: i64, difficulty: i64) -> bool {
+        self.level.is_none_or(|l| l == level)
+            && self.difficulty.is_none_or(|d| d == difficulty)
+            && self.chart.is_none_or(|c| c == chart)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct NewGoal {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub target: GoalTarget,
+    #[serde(flatten)]
+    pub scope: GoalScope,
+    /// 達成が必要な譜面数 (None なら範囲内の全譜面)
+    pub count: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RemainingChart {
+    pub chart: i64,
+    pub title: String,
+    pub difficulty: i64,
+    pub level: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GoalProgress {
+    pub id: i64,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub target: GoalTarget,
+    #[serde(flatten)]
+    pub scope: GoalScope,
+    pub count: Option<i64>,
+    pub achieved: i64,
+    pub required: i64,
+    pub percentage: f64,
+    pub completed: bool,
+    pub remaining: Vec<RemainingChart>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CompletedGoal {
+    pub id: i64,
+    pub name: Option<String>,
+}
+
+/// 範囲内の譜面と自己ベスト (未プレイなら値はすべて None)
+struct BestRow {
+    chart: i64,
+    title: String,
+    difficulty: i64,
+    level: i64,
+    values: ScoreValues,
+}
+
+struct StoredGoal {
+    id: i64,
+    name: Option<String>,
+    target: GoalTarget,
+    scope: GoalScope,
+    count: Option<i64>,
+}
+
+pub async fn add_goal(pool: &SqlitePool, user_id: i64, goal: &NewGoal) -> Result<i64> {
+    goal.target.validate()?;
+    if goal.count.is_some_and(|c| c <= 0) {
+        return Err(anyhow!("Goal count must be positive"));
+    }
+
+    let (target, value) = goal.target.to_row();
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let id = sqlx::query!(
+        r"insert into goal (user, name, target, value, level, difficulty, chart, count, created_at)
+        values (?, ?, ?, ?, ?, ?, ?, ?, ?) returning id",
+        user_id,
+        goal.name,
+        target,
+        value,
+        goal.scope.level,
+        goal.scope.difficulty,
+        goal.scope.chart,
+        goal.count,
+        now
+    )
+    .fetch_one(pool)
+    .await?
+    .id;
+
+    Ok(id)
+}
+
+pub async fn delete_goal(pool: &SqlitePool, user_id: i64, goal_id: i64) -> Result<()> {
+    let res = sqlx::query!(
+        r"delete from goal where id = ? and user = ?",
+        goal_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(NotFound(format!("Unknown goal: {}", goal_id)).into());
+    }
+    Ok(())
+}
+
+async fn load_goals(pool: &SqlitePool, user_id: i64) -> Result<Vec<StoredGoal>> {
+    sqlx::query!(
+        r"select id, name, target, value, level, difficulty, chart, count
+        from goal where user = ? order by id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|g| {
+        Ok(StoredGoal {
+            id: g.id,
+            name: g.name,
+            target: GoalTarget::from_row(&g.target, &g.value)?,
+            scope: GoalScope {
+                level: g.level,
+                difficulty: g.difficulty,
+                chart: g.chart,
+            },
+            count: g.count,
+        })
+    })
+    .collect()
+}
+
+/// `scope` の範囲の譜面と自己ベスト
+async fn load_bests(pool: &SqlitePool, user_id: i64, scope: &GoalScope) -> Result<Vec<BestRow>> {
+    Ok(sqlx::query!(
+        r"select
+            chart.id as chart,
+            song.name as title,
+            chart.difficulty,
+            chart.level,
+            bs.score,
+            bs.ex_score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank,
+            bs.flare_skill
+        from
+            chart
+        inner join song on song.id = chart.song
+        left join best on best.chart = chart.id and best.user = ?1
+        left join score as bs on bs.id = best.score
+        where
+            (?2 is null or chart.level = ?2)
+            and (?3 is null or chart.difficulty = ?3)
+            and (?4 is null or chart.id = ?4)
+        order by chart.level, song.name, chart.difficulty",
+        user_id,
+        scope.level,
+        scope.difficulty,
+        scope.chart
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| BestRow {
+        chart: r.chart,
+        title: r.title,
+        difficulty: r.difficulty,
+        level: r.level,
+        values: ScoreValues {
+            score: r.score,
+            ex_score: r.ex_score,
+            clear_rank: r.clear_rank,
+            clear_kind: r.clear_kind,
+            flare_rank: r.flare_rank,
+            flare_skill: r.flare_skill,
+        },
+    })
+    .collect())
+}
+
+/// `bests` は少なくとも目標の範囲の譜面をすべて含むこと
+fn progress(g: StoredGoal, bests: &[BestRow]) -> GoalProgress {
+    let mut achieved = 0;
+    let mut total = 0;
+    let mut remaining = vec![];
+    for b in bests
+        .iter()
+        .filter(|b| g.scope.contains(b.chart, b.level, b.difficulty))
+    {
+        total += 1;
+        if g.target.is_met(&b.values) {
+            achieved += 1;
+        } else {
+            remaining.push(RemainingChart {
+                chart: b.chart,
+                title: b.title.clone(),
+                difficulty: b.difficulty,
+                level: b.level,
+            });
+        }
+    }
+
+    let required = g.count.unwrap_or(total);
+    let completed = required > 0 && achieved >= required;
+    let percentage = if required > 0 {
+        achieved.min(required) as f64 * 100.0 / required as f64
+    } else {
+        0.0
+    };
+
+    GoalProgress {
+        id: g.id,
+        name: g.name,
+        target: g.target,
+        scope: g.scope,
+        count: g.count,
+        achieved,
+        required,
+        percentage,
+        completed,
+        remaining: if completed { vec![] } else { remaining },
+    }
+}
+
+pub async fn list_goals(pool: &SqlitePool, user_id: i64) -> Result<Vec<GoalProgress>> {
+    let goals = load_goals(pool, user_id).await?;
+    if goals.is_empty() {
+        return Ok(vec![]);
+    }
+    let bests = load_bests(pool, user_id, &GoalScope::default()).await?;
+    Ok(goals.into_iter().map(|g| progress(g, &bests)).collect())
+}
+
+/// 今回の自己ベスト更新で達成した目標
+///
+/// 達成状況が変わりうるのは更新した譜面を範囲に含み、その譜面で新たに条件を満たした目標だけなので、
+/// それらの目標の範囲の譜面だけを読み直す
+#[tracing::instrument(skip_all)]
+pub async fn newly_completed(
+    pool: &SqlitePool,
+    user_id: i64,
+    improvements: &[Improvement],
+) -> Result<Vec<CompletedGoal>> {
+    if improvements.is_empty() {
+        return Ok(vec![]);
+    }
+    let goals = load_goals(pool, user_id).await?;
+
+    let mut res = vec![];
+    for g in goals {
+        // この更新で新たに条件を満たした譜面の数
+        let gained = improvements
+            .iter()
+            .filter(|i| {
+                let difficulty = i.difficulty.parse::<Difficulty>().map(|d| d as i64);
+                difficulty.is_ok_and(|d| g.scope.contains(i.chart_id, i.level, d))
+                    && g.target.is_met(&i.current)
+                    && !i.previous.as_ref().is_some_and(|p| g.target.is_met(p))
+            })
+            .count() as i64;
+        if gained == 0 {
+            continue;
+        }
+
+        let bests = load_bests(pool, user_id, &g.scope).await?;
+        let p = progress(g, &bests);
+        if p.completed && p.achieved - gained < p.required {
+            res.push(CompletedGoal {
+                id: p.id,
+                name: p.name,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::RequestSongData,
+        catalog::{self, CatalogData},
+        db,
+        scores::{self, RequestScoreData},
+    };
+
+    struct Fixture {
+        pool: SqlitePool,
+        catalog: CatalogData,
+        user_id: i64,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let pool = db::test_pool().await;
+            catalog::add_songs(
+                &pool,
+                &[
+                    RequestSongData {
+                        name: "PARANOiA".to_owned(),
+                        version: "1st".to_owned(),
+                        levels: [None, None, None, Some(14), Some(16)],
+                    },
+                    RequestSongData {
+                        name: "MAX 300".to_owned(),
+                        version: "3rd".to_owned(),
+                        levels: [None, None, None, Some(14), None],
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+            let user_id = sqlx::query_scalar::<_, i64>(
+                "insert into user (name, password_hash) values ('alice', '') returning id",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            let catalog = CatalogData::load(&pool).await.unwrap();
+            Self {
+                pool,
+                catalog,
+                user_id,
+            }
+        }
+
+        async fn add_goal(&self, target: GoalTarget, scope: GoalScope, count: Option<i64>) -> i64 {
+            let goal = NewGoal {
+                name: None,
+                target,
+                scope,
+                count,
+            };
+            add_goal(&self.pool, self.user_id, &goal).await.unwrap()
+        }
+
+        /// 記録を登録し、それで達成した目標の ID を返す
+        async fn play(&self, title: &str, difficulty: &str, score: i64, rank: &str) -> Vec<i64> {
+            let record = RequestScoreData {
+                title: title.to_owned(),
+                difficulty: difficulty.to_owned(),
+                score: Some(score),
+                ex_score: None,
+                rank: Some(rank.to_owned()),
+                clear_kind: Some("CLEAR".to_owned()),
+                flare_skill: None,
+                flare_rank: None,
+                played_at: None,
+            };
+            let outcome = scores::update_scores(&self.pool, &self.catalog, self.user_id, &[record])
+                .await
+                .unwrap();
+            newly_completed(&self.pool, self.user_id, &outcome.improvements)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.id)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_goal_once_when_the_count_is_reached() {
+        let f = Fixture::new().await;
+        let scope = GoalScope {
+            level: Some(14),
+            ..Default::default()
+        };
+        let goal = f.add_goal(GoalTarget::Score(900_000), scope, Some(2)).await;
+
+        // ちょうど目標のスコアは達成だが、譜面数がまだ足りない
+        assert!(f.play("PARANOiA", "EXPERT", 900_000, "AA").await.is_empty());
+        assert!(f.play("MAX 300", "EXPERT", 899_999, "AA-").await.is_empty());
+        // 範囲外の譜面は数えない
+        assert!(f
+            .play("PARANOiA", "CHALLENGE", 990_000, "AAA")
+            .await
+            .is_empty());
+        assert_eq!(f.play("MAX 300", "EXPERT", 900_000, "AA").await, vec![goal]);
+        // 達成済みの目標は再び報告しない
+        assert!(f.play("MAX 300", "EXPERT", 990_000, "AAA").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compares_clear_rank_at_the_boundary() {
+        let f = Fixture::new().await;
+        let chart = sqlx::query_scalar::<_, i64>(
+            "select chart.id from chart inner join song on song.id = chart.song
+            where song.name = 'PARANOiA' and chart.difficulty = 4",
+        )
+        .fetch_one(&f.pool)
+        .await
+        .unwrap();
+        let scope = GoalScope {
+            chart: Some(chart),
+            ..Default::default()
+        };
+        let goal = f
+            .add_goal(GoalTarget::ClearRank("aa".to_owned()), scope, None)
+            .await;
+
+        assert!(f
+            .play("PARANOiA", "CHALLENGE", 899_990, "AA-")
+            .await
+            .is_empty());
+        assert_eq!(
+            f.play("PARANOiA", "CHALLENGE", 900_000, "AA").await,
+            vec![goal]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_empty_updates() {
+        let f = Fixture::new().await;
+        f.add_goal(GoalTarget::Score(0), GoalScope::default(), None)
+            .await;
+        assert!(newly_completed(&f.pool, f.user_id, &[])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{
+    catalog::CatalogData,
+    scores::{self, RequestScoreData},
+    ClearKind, ClearRank, Difficulty,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ParsedImport {
+    pub records: Vec<RequestScoreData>,
+    /// 読み飛ばした行など
+    pub warnings: Vec<String>,
+}
+
+/// 他のスコアツールのエクスポートを読み込む
+pub trait Importer: Send + Sync {
+    fn parse(&self, raw: &[u8]) -> Result<ParsedImport>;
+}
+
+/// 形式名から取り込み方法を選ぶ
+pub fn importer(format: &str) -> Result<Box<dyn Importer>> {
+    match format.to_ascii_lowercase().as_str() {
+        "csv" => Ok(Box::new(CommunityCsv)),
+        "json" => Ok(Box::new(CommunityJson)),
+        _ => Err(anyhow!("Unknown import format {}", format)),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ImportReport {
+    pub parsed: usize,
+    pub updated: usize,
+    pub unmatched: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// `update_score` と同じ経路で登録する
+pub async fn import(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    importer: &dyn Importer,
+    raw: &[u8],
+) -> Result<ImportReport> {
+    let parsed = importer.parse(raw)?;
+    let outcome = scores::update_scores(pool, catalog, user_id, &parsed.records).await?;
+
+    let mut errors = parsed.warnings;
+    errors.extend(outcome.errors);
+    Ok(ImportReport {
+        parsed: parsed.records.len(),
+        updated: outcome.updated,
+        unmatched: outcome.unmatched,
+        errors,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Flare {
+    Number(i64),
+    Text(String),
+}
+
+/// よく使われる title / difficulty / score / lamp / date 形式の 1 行
+#[derive(Debug, Clone, Deserialize)]
+struct CommunityRow {
+    #[serde(alias = "song", alias = "name")]
+    title: String,
+    #[serde(alias = "diff", alias = "chart")]
+    difficulty: String,
+    #[serde(alias = "points")]
+    score: Option<i64>,
+    #[serde(alias = "clear", alias = "clear_type", alias = "clear_kind")]
+    lamp: Option<String>,
+    #[serde(alias = "timestamp", alias = "played_at", alias = "time")]
+    date: Option<String>,
+    flare: Option<Flare>,
+}
+
+fn normalize_difficulty(s: &str) -> String {
+    let s = s.trim();
+    // 小文字の b は BEGINNER (bSP)
+    if s == "b" || s == "bSP" {
+        return Difficulty::Beginner.to_string();
+    }
+    let d = match s.to_ascii_uppercase().as_str() {
+        "BEGINNER" | "BEG" => Difficulty::Beginner,
+        "BASIC" | "BAS" | "BSP" | "B" | "LIGHT" => Difficulty::Basic,
+        "DIFFICULT" | "DIF" | "DSP" | "D" | "STANDARD" => Difficulty::Difficult,
+        "EXPERT" | "EXP" | "ESP" | "E" | "HEAVY" => Difficulty::Expert,
+        "CHALLENGE" | "CHA" | "CSP" | "C" | "ONI" => Difficulty::Challenge,
+        // 不明なものはそのまま渡して登録時にエラーとする
+        _ => return s.to_owned(),
+    };
+    d.to_string()
+}
+
+fn normalize_lamp(s: &str) -> Result<Option<ClearKind>> {
+    let key = s
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+        .collect::<String>()
+        .to_ascii_uppercase();
+    Ok(Some(match key.as_str() {
+        "" | "NOPLAY" => return Ok(None),
+        "MFC" | "MARVELOUSFULLCOMBO" => ClearKind::MFC,
+        "PFC" | "PERFECTFULLCOMBO" => ClearKind::PFC,
+        "GFC" | "GREATFULLCOMBO" => ClearKind::GFC,
+        "FC" | "GOODFULLCOMBO" | "FULLCOMBO" => ClearKind::FC,
+        "LIFE4" | "L4" => ClearKind::Life4,
+        "CLEAR" | "CLEARED" | "PASS" => ClearKind::Clear,
+        "ASSISTED" | "ASSISTEDCLEAR" => ClearKind::Assisted,
+        "FAILED" | "FAIL" => ClearKind::Failed,
+        _ => return Err(anyhow!("Unknown lamp {}", s)),
+    }))
+}
+
+fn normalize_flare(f: &Flare) -> Result<i64> {
+    let rank = match f {
+        Flare::Number(n) => *n,
+        Flare::Text(t) => match t.trim().to_ascii_uppercase().as_str() {
+            "EX" => 10,
+            "IX" => 9,
+            "VIII" => 8,
+            "VII" => 7,
+            "VI" => 6,
+            "V" => 5,
+            "IV" => 4,
+            "III" => 3,
+            "II" => 2,
+            "I" => 1,
+            "" | "NONE" | "0" => 0,
+            t => t.parse()?,
+        },
+    };
+    if (0..=10).contains(&rank) {
+        Ok(rank)
+    } else {
+        Err(anyhow!("Unknown flare rank {}", rank))
+    }
+}
+
+/// タイムゾーンの無い日時のタイムゾーン (ゲームの出力は JST)
+const NAIVE_DATE_OFFSET: i32 = 9 * 3600;
+
+fn parse_date(s: &str) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(s) {
+        return Ok(d.to_utc());
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").or_else(|_| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap_or_default())
+    });
+    match naive {
+        Ok(d) => Ok(d
+            .and_local_timezone(FixedOffset::east_opt(NAIVE_DATE_OFFSET).unwrap())
+            .single()
+            .ok_or_else(|| anyhow!("Invalid date {}", s))?
+            .to_utc()),
+        Err(_) => Err(anyhow!("Unknown date {}", s)),
+    }
+}
+
+impl CommunityRow {
+    fn into_record(self) -> Result<RequestScoreData> {
+        let lamp = match &self.lamp {
+            Some(l) => normalize_lamp(l)?,
+            None => None,
+        };
+        let score = self.score.filter(|s| *s > 0);
+        let rank = match lamp {
+            Some(ClearKind::Failed) => Some(ClearRank::E),
+            _ => score.map(ClearRank::from_score),
+        };
+        let flare_rank = self.flare.as_ref().map(normalize_flare).transpose()?;
+        let played_at = self.date.as_deref().map(parse_date).transpose()?;
+
+        Ok(RequestScoreData {
+            title: self.title,
+            difficulty: normalize_difficulty(&self.difficulty),
+            score,
+            ex_score: None,
+            rank: rank.map(|r| r.to_string()),
+            clear_kind: lamp.map(|l| l.to_string()),
+            flare_skill: None,
+            flare_rank,
+            played_at,
+        })
+    }
+}
+
+fn convert(rows: Vec<(usize, Result<CommunityRow>)>) -> ParsedImport {
+    let mut res = ParsedImport::default();
+    for (line, row) in rows {
+        match row.and_then(|r| r.into_record()) {
+            Ok(r) => res.records.push(r),
+            Err(e) => res.warnings.push(format!("[row {}] {}", line, e)),
+        }
+    }
+    res
+}
+
+/// ヘッダ付き CSV
+pub struct CommunityCsv;
+
+impl Importer for CommunityCsv {
+    fn parse(&self, raw: &[u8]) -> Result<ParsedImport> {
+        let mut r = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(raw);
+        let rows = r
+            .deserialize::<CommunityRow>()
+            .enumerate()
+            .map(|(i, row)| (i + 1, row.map_err(Into::into)))
+            .collect();
+        Ok(convert(rows))
+    }
+}
+
+/// オブジェクトの配列の JSON
+pub struct CommunityJson;
+
+impl Importer for CommunityJson {
+    fn parse(&self, raw: &[u8]) -> Result<ParsedImport> {
+        let values: Vec<serde_json::Value> = serde_json::from_slice(raw)?;
+        let rows = values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i + 1, serde_json::from_value(v).map_err(Into::into)))
+            .collect();
+        Ok(convert(rows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_reads_naive_dates_as_jst() {
+        // JST の 9 時より前は UTC では前日
+        assert_eq!(
+            parse_date("2024-03-01 08:30:00").unwrap(),
+            DateTime::parse_from_rfc3339("2024-02-29T23:30:00Z").unwrap()
+        );
+        assert_eq!(
+            parse_date("2024-03-01").unwrap(),
+            DateTime::parse_from_rfc3339("2024-02-29T15:00:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_date_keeps_explicit_offsets() {
+        assert_eq!(
+            parse_date(" 2024-03-01T08:30:00+02:00 ").unwrap(),
+            DateTime::parse_from_rfc3339("2024-03-01T06:30:00Z").unwrap()
+        );
+        assert!(parse_date("03/01/2024").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+
+/// 実行を始めた依頼を他が取らない時間 (プロセスが止まったままこれを過ぎたら取り直す)
+pub const LEASE: Duration = Duration::from_secs(300);
+
+/// DB に残した依頼をバックグラウンドで処理するもの
+///
+/// 依頼は `next_attempt_at` (unix 時刻) を持ち、取るときに `lease_until` を `LEASE` 先にすることで
+/// 複数のプロセスが同じ依頼を同時に処理しないようにする
+pub trait JobRunner: Clone + Send + Sync + 'static {
+    /// このプロセスで処理中か
+    fn draining(&self) -> &AtomicBool;
+
+    /// 最も早い実行待ちの依頼の時刻 (他のプロセスが実行中のものは除く)
+    fn next_due(&self) -> impl Future<Output = Result<Option<i64>>> + Send;
+
+    /// 時刻の来た依頼を 1 つ取って処理する (無ければ false)
+    fn run_next(&self) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// 実行待ちの依頼があればバックグラウンドで処理する (処理中なら何もしない)
+///
+/// Lambda ではレスポンスを返すと止まるため、呼び出しのたびにこれを呼んで続きを実行する
+pub fn resume<R: JobRunner>(runner: &R) {
+    if runner.draining().swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let runner = runner.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = drain(&runner).await {
+                tracing::warn!("failed to process background jobs: {:#}", e);
+            }
+            runner.draining().store(false, Ordering::Release);
+            // 終わる間際に来た依頼を取りこぼさない
+            let due = runner.next_due().await.ok().flatten();
+            if due.is_none_or(|t| t > Utc::now().timestamp())
+                || runner.draining().swap(true, Ordering::AcqRel)
+            {
+                break;
+            }
+        }
+    });
+}
+
+/// 実行待ちの依頼が無くなるまで処理する (再試行待ちは時刻まで待つ)
+async fn drain(runner: &impl JobRunner) -> Result<()> {
+    loop {
+        if runner.run_next().await? {
+            continue;
+        }
+        let Some(due) = runner.next_due().await? else {
+            return Ok(());
+        };
+        let wait = (due - Utc::now().timestamp()).max(1) as u64;
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+/// `attempt` 回目が失敗した後、再試行までの待ち時間 (試すたびに倍にする)
+pub fn retry_delay(base: Duration, attempt: i64) -> Duration {
+    base * 2u32.pow((attempt - 1).clamp(0, 16) as u32)
+}
+
+/// 取った依頼に設定する `lease_until`
+pub fn lease_until() -> i64 {
+    Utc::now().timestamp() + LEASE.as_secs() as i64
+}
@@ -0,0 +1,214 @@
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::NotFound;
+
+/// ランキングの並び順に使う値
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardKey {
+    #[default]
+    Score,
+    ExScore,
+    FlareSkill,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChartLeaderboardEntry {
+    pub rank: usize,
+    pub user: String,
+    pub score: Option<i64>,
+    pub ex_score: Option<i64>,
+    pub clear_rank: Option<String>,
+    pub clear_kind: Option<String>,
+    pub flare_rank: Option<i64>,
+    pub flare_skill: Option<i64>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChartLeaderboard {
+    pub chart: i64,
+    pub title: String,
+    pub difficulty: i64,
+    pub level: i64,
+    pub entries: Vec<ChartLeaderboardEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LevelSummaryEntry {
+    pub rank: usize,
+    pub user: String,
+    pub charts: i64,
+    pub total_score: i64,
+    pub total_ex_score: i64,
+    pub total_flare_skill: i64,
+    pub aaa: i64,
+    pub pfc: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LevelSummary {
+    pub level: i64,
+    pub entries: Vec<LevelSummaryEntry>,
+}
+
+/// 値の降順、同値なら先に達成した方 (`created_at` が早い方) を上位とする
+fn rank_by<T, F>(mut rows: Vec<(Option<i64>, String, T)>, f: F) -> Vec<T>
+where
+    F: Fn(usize, &mut T),
+{
+    rows.retain(|(v, _, _)| v.is_some());
+    rows.sort_by(|(va, ca, _), (vb, cb, _)| (Reverse(va), ca).cmp(&(Reverse(vb), cb)));
+    rows.into_iter()
+        .enumerate()
+        .map(|(i, (_, _, mut e))| {
+            f(i + 1, &mut e);
+            e
+        })
+        .collect()
+}
+
+pub async fn chart_leaderboard(
+    pool: &SqlitePool,
+    chart_id: i64,
+    key: LeaderboardKey,
+) -> Result<ChartLeaderboard> {
+    let chart = sqlx::query!(
+        r"select
+            chart.id,
+            song.name as title,
+            chart.difficulty,
+            chart.level
+        from
+            chart
+        inner join song on song.id = chart.song
+        where
+            chart.id = ?",
+        chart_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| NotFound(format!("Unknown chart: {}", chart_id)))?;
+
+    // ランキング参加を許可した公開ユーザーの自己ベストのみ
+    let rows = sqlx::query!(
+        r"select
+            user.name,
+            bs.score,
+            bs.ex_score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank,
+            bs.flare_skill,
+            bs.created_at
+        from
+            best
+        inner join score as bs on bs.id = best.score
+        inner join user on user.id = best.user
+        inner join user_setting as us on us.user = best.user
+        where
+            best.chart = ?
+            and us.leaderboard = 1
+            and us.visibility = 'public'",
+        chart_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let value = match key {
+            LeaderboardKey::Score => r.score,
+            LeaderboardKey::ExScore => r.ex_score,
+            LeaderboardKey::FlareSkill => r.flare_skill,
+        };
+        (
+            value,
+            r.created_at.clone(),
+            ChartLeaderboardEntry {
+                rank: 0,
+                user: r.name,
+                score: r.score,
+                ex_score: r.ex_score,
+                clear_rank: r.clear_rank,
+                clear_kind: r.clear_kind,
+                flare_rank: r.flare_rank,
+                flare_skill: r.flare_skill,
+                created_at: r.created_at,
+            },
+        )
+    })
+    .collect::<Vec<_>>();
+
+    Ok(ChartLeaderboard {
+        chart: chart.id,
+        title: chart.title,
+        difficulty: chart.difficulty,
+        level: chart.level,
+        entries: rank_by(rows, |rank, e| e.rank = rank),
+    })
+}
+
+pub async fn level_summary(
+    pool: &SqlitePool,
+    level: i64,
+    key: LeaderboardKey,
+) -> Result<LevelSummary> {
+    let rows = sqlx::query!(
+        r#"select
+            user.name as "name!: String",
+            count(*) as "charts!: i64",
+            sum(ifnull(bs.score, 0)) as "total_score!: i64",
+            sum(ifnull(bs.ex_score, 0)) as "total_ex_score!: i64",
+            sum(ifnull(bs.flare_skill, 0)) as "total_flare_skill!: i64",
+            sum(bs.clear_rank = 'AAA') as "aaa!: i64",
+            sum(bs.clear_kind in ('MFC', 'PFC')) as "pfc!: i64",
+            max(bs.created_at) as "last_update!: String"
+        from
+            best
+        inner join score as bs on bs.id = best.score
+        inner join chart on chart.id = best.chart
+        inner join user on user.id = best.user
+        inner join user_setting as us on us.user = best.user
+        where
+            chart.level = ?
+            and us.leaderboard = 1
+            and us.visibility = 'public'
+        group by best.user"#,
+        level
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let value = match key {
+            LeaderboardKey::Score => r.total_score,
+            LeaderboardKey::ExScore => r.total_ex_score,
+            LeaderboardKey::FlareSkill => r.total_flare_skill,
+        };
+        (
+            Some(value),
+            r.last_update,
+            LevelSummaryEntry {
+                rank: 0,
+                user: r.name,
+                charts: r.charts,
+                total_score: r.total_score,
+                total_ex_score: r.total_ex_score,
+                total_flare_skill: r.total_flare_skill,
+                aaa: r.aaa,
+                pfc: r.pfc,
+            },
+        )
+    })
+    .collect::<Vec<_>>();
+
+    Ok(LevelSummary {
+        level,
+        entries: rank_by(rows, |rank, e| e.rank = rank),
+    })
+}
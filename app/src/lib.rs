@@ -1,3 +1,28 @@
+pub mod api;
+pub mod backup;
+pub mod catalog;
+pub mod client;
+pub mod db;
+pub mod dump;
+pub mod dump_job;
+pub mod export;
+pub mod goals;
+pub mod import;
+pub mod job_queue;
+pub mod leaderboard;
+pub mod metrics;
+pub mod readiness;
+pub mod recommend;
+pub mod restore;
+pub mod scores;
+pub mod server;
+pub mod settings;
+pub mod stats;
+pub mod storage;
+pub mod telemetry;
+pub mod timeline;
+pub mod webhook;
+
 use std::{fmt::Display, str::FromStr};
 
 use anyhow::anyhow;
@@ -42,7 +67,17 @@ impl Display for Difficulty {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Difficulty {
+    pub const ALL: [Difficulty; 5] = [
+        Self::Beginner,
+        Self::Basic,
+        Self::Difficult,
+        Self::Expert,
+        Self::Challenge,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ClearRank {
     AAA = 0,
     AAPlus = 1,
@@ -88,7 +123,87 @@ impl FromStr for ClearRank {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Display for ClearRank {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AAA => write!(f, "AAA"),
+            Self::AAPlus => write!(f, "AA+"),
+            Self::AA => write!(f, "AA"),
+            Self::AAMinus => write!(f, "AA-"),
+            Self::APlus => write!(f, "A+"),
+            Self::A => write!(f, "A"),
+            Self::AMinus => write!(f, "A-"),
+            Self::BPlus => write!(f, "B+"),
+            Self::B => write!(f, "B"),
+            Self::BMinus => write!(f, "B-"),
+            Self::CPlus => write!(f, "C+"),
+            Self::C => write!(f, "C"),
+            Self::CMinus => write!(f, "C-"),
+            Self::DPlus => write!(f, "D+"),
+            Self::D => write!(f, "D"),
+            Self::E => write!(f, "E"),
+        }
+    }
+}
+
+impl ClearRank {
+    /// 良い順
+    pub const ALL: [ClearRank; 16] = [
+        Self::AAA,
+        Self::AAPlus,
+        Self::AA,
+        Self::AAMinus,
+        Self::APlus,
+        Self::A,
+        Self::AMinus,
+        Self::BPlus,
+        Self::B,
+        Self::BMinus,
+        Self::CPlus,
+        Self::C,
+        Self::CMinus,
+        Self::DPlus,
+        Self::D,
+        Self::E,
+    ];
+
+    /// このランクに必要な最低スコア (E はクリア失敗なので None)
+    pub fn min_score(&self) -> Option<i64> {
+        match self {
+            Self::AAA => Some(990000),
+            Self::AAPlus => Some(950000),
+            Self::AA => Some(900000),
+            Self::AAMinus => Some(890000),
+            Self::APlus => Some(850000),
+            Self::A => Some(800000),
+            Self::AMinus => Some(790000),
+            Self::BPlus => Some(750000),
+            Self::B => Some(700000),
+            Self::BMinus => Some(690000),
+            Self::CPlus => Some(650000),
+            Self::C => Some(600000),
+            Self::CMinus => Some(590000),
+            Self::DPlus => Some(550000),
+            Self::D => Some(0),
+            Self::E => None,
+        }
+    }
+
+    /// 一つ上のランク
+    pub fn next(&self) -> Option<ClearRank> {
+        (*self as usize).checked_sub(1).map(|i| Self::ALL[i])
+    }
+
+    /// スコアから決まるランク (クリアしている前提)
+    pub fn from_score(score: i64) -> ClearRank {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.min_score().is_some_and(|m| m <= score))
+            .unwrap_or(Self::D)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ClearKind {
     MFC = 0,
     PFC = 1,
@@ -119,12 +234,111 @@ impl FromStr for ClearKind {
         }
     }
 }
+
+impl Display for ClearKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MFC => write!(f, "MFC"),
+            Self::PFC => write!(f, "PFC"),
+            Self::GFC => write!(f, "GFC"),
+            Self::FC => write!(f, "FC"),
+            Self::Life4 => write!(f, "LIFE4"),
+            Self::Clear => write!(f, "CLEAR"),
+            Self::Assisted => write!(f, "ASSISTED"),
+            Self::Failed => write!(f, "FAILED"),
+            Self::NoPlay => write!(f, "NO PLAY"),
+        }
+    }
+}
+
+impl ClearKind {
+    /// 良い順
+    pub const ALL: [ClearKind; 9] = [
+        Self::MFC,
+        Self::PFC,
+        Self::GFC,
+        Self::FC,
+        Self::Life4,
+        Self::Clear,
+        Self::Assisted,
+        Self::Failed,
+        Self::NoPlay,
+    ];
+
+    /// 一つ上のクリア種別
+    pub fn next(&self) -> Option<ClearKind> {
+        (*self as usize).checked_sub(1).map(|i| Self::ALL[i])
+    }
+}
+
 pub struct ApiError(anyhow::Error);
 
 pub type ApiResult<T, E = ApiError> = std::result::Result<T, E>;
 
+/// 既存のデータと衝突した (409 Conflict で返す)
+#[derive(Debug)]
+pub struct Conflict(pub String);
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// 閲覧が許可されていない (403 Forbidden で返す)
+#[derive(Debug)]
+pub struct Forbidden(pub String);
+
+impl Display for Forbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Forbidden {}
+
+/// 指定されたユーザーなどが存在しない (404 Not Found で返す)
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
+        if let Some(c) = self.0.downcast_ref::<Conflict>() {
+            tracing::warn!("conflict: {}", c);
+            return (StatusCode::CONFLICT, format!("Conflict: {}", c)).into_response();
+        }
+        if let Some(e) = self.0.downcast_ref::<Forbidden>() {
+            tracing::warn!("forbidden: {}", e);
+            return (StatusCode::FORBIDDEN, format!("Forbidden: {}", e)).into_response();
+        }
+        if let Some(e) = self.0.downcast_ref::<NotFound>() {
+            tracing::warn!("not found: {}", e);
+            return (StatusCode::NOT_FOUND, format!("Not found: {}", e)).into_response();
+        }
+        // 一意制約・外部キー制約の違反
+        if let Some(e) = self
+            .0
+            .downcast_ref::<sqlx::Error>()
+            .and_then(|e| e.as_database_error())
+        {
+            if e.is_unique_violation() || e.is_foreign_key_violation() {
+                tracing::warn!("conflict: {}", e.message());
+                return (StatusCode::CONFLICT, format!("Conflict: {}", e.message()))
+                    .into_response();
+            }
+        }
+
+        tracing::error!("request failed: {:#}", self.0);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
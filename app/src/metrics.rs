@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SYNC_SIZE_BUCKETS: [f64; 8] = [1.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0];
+
+/// (名前, 種類, 説明)
+const DESCRIPTIONS: [(&str, &str, &str); 5] = [
+    ("http_requests_total", "counter", "Number of HTTP requests."),
+    (
+        "http_request_errors_total",
+        "counter",
+        "Number of HTTP requests answered with a 4xx or 5xx status.",
+    ),
+    (
+        "http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency.",
+    ),
+    (
+        "sync_records",
+        "histogram",
+        "Number of records sent in one score sync.",
+    ),
+    (
+        "sync_updated_total",
+        "counter",
+        "Number of records that updated a personal best.",
+    ),
+];
+
+/// `key="value",...` (キーの順に並べる)
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let mut labels = labels.to_vec();
+    labels.sort();
+    labels
+        .iter()
+        .map(|(k, v)| {
+            format!(
+                "{}=\"{}\"",
+                k,
+                v.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 足し込むだけのカウンタの集まり
+///
+/// ヒストグラムも `_bucket`・`_sum`・`_count` のカウンタとして持つので、
+/// 各プロセスの値は `metric` テーブルに足し込めば合計になる。
+/// Lambda では public と private が別のプロセスなので、まとめて private の `/metrics` で出力する。
+/// 書き込み用の接続を塞がないよう、書き出すのは `/metrics` の出力前と終了時だけにする
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// 書き出していない増分 ((名前, ラベル) → 値)
+    pending: Arc<Mutex<BTreeMap<(String, String), f64>>>,
+}
+
+impl Metrics {
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending
+            .entry((name.to_owned(), format_labels(labels)))
+            .or_default() += value;
+    }
+
+    /// 範囲外のバケットにも 0 を足し、系列の全バケットが揃って出力されるようにする
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], buckets: &[f64], value: f64) {
+        for le in buckets {
+            let count = if value <= *le { 1.0 } else { 0.0 };
+            let le = le.to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.add(&format!("{}_bucket", name), &bucket_labels, count);
+        }
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", "+Inf"));
+        self.add(&format!("{}_bucket", name), &bucket_labels, 1.0);
+        self.add(&format!("{}_sum", name), labels, value);
+        self.add(&format!("{}_count", name), labels, 1.0);
+    }
+
+    pub fn record_request(&self, service: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        self.add(
+            "http_requests_total",
+            &[("service", service), ("route", route), ("status", &status)],
+            1.0,
+        );
+        if status.starts_with('4') || status.starts_with('5') {
+            self.add(
+                "http_request_errors_total",
+                &[("service", service), ("route", route), ("status", &status)],
+                1.0,
+            );
+        }
+        self.observe(
+            "http_request_duration_seconds",
+            &[("service", service), ("route", route)],
+            &LATENCY_BUCKETS,
+            elapsed.as_secs_f64(),
+        );
+    }
+
+    /// スコアの同期 1 回分
+    pub fn record_sync(&self, source: &str, records: usize, updated: usize) {
+        self.observe(
+            "sync_records",
+            &[("source", source)],
+            &SYNC_SIZE_BUCKETS,
+            records as f64,
+        );
+        self.add("sync_updated_total", &[("source", source)], updated as f64);
+    }
+
+    /// 増分を `metric` テーブルに足し込む
+    pub async fn flush(&self, pool: &SqlitePool) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let res: Result<()> = async {
+            let mut tx = pool.begin().await?;
+            for ((name, labels), value) in &pending {
+                sqlx::query!(
+                    r"insert into metric (name, labels, value) values (?, ?, ?)
+                    on conflict (name, labels) do update set value = value + excluded.value",
+                    name,
+                    labels,
+                    value
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        }
+        .await;
+
+        // 書き出せなかった分は次に回す
+        if res.is_err() {
+            let mut cur = self.pending.lock().unwrap();
+            for (key, value) in pending {
+                *cur.entry(key).or_default() += value;
+            }
+        }
+        res
+    }
+}
+
+/// `_bucket`・`_sum`・`_count` を除いた名前
+fn base_name(name: &str) -> &str {
+    ["_bucket", "_sum", "_count"]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .filter(|base| {
+            DESCRIPTIONS
+                .iter()
+                .any(|(n, kind, _)| n == base && *kind == "histogram")
+        })
+        .unwrap_or(name)
+}
+
+/// `le` を除いたラベルと `le` の値
+fn split_le(labels: &str) -> (String, f64) {
+    let mut le = f64::NAN;
+    let rest = labels
+        .split(',')
+        .filter(|l| match l.strip_prefix("le=\"") {
+            Some(v) => {
+                le = v.trim_end_matches('"').parse().unwrap_or(f64::INFINITY);
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    (rest, le)
+}
+
+/// `metric` テーブルの値を Prometheus のテキスト形式で出力する
+pub async fn render(pool: &SqlitePool) -> Result<String> {
+    let mut rows = sqlx::query!(r"select name, labels, value from metric")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| {
+            let (rest, le) = split_le(&r.labels);
+            (
+                base_name(&r.name).to_owned(),
+                rest,
+                le,
+                r.name,
+                r.labels,
+                r.value,
+            )
+        })
+        .collect::<Vec<_>>();
+    // ヒストグラムは系列ごとに le の小さい順、続けて _sum・_count
+    rows.sort_by(|a, b| {
+        (&a.0, &a.1)
+            .cmp(&(&b.0, &b.1))
+            .then(a.2.is_nan().cmp(&b.2.is_nan()))
+            .then(a.2.total_cmp(&b.2))
+            .then(a.3.cmp(&b.3))
+    });
+
+    let mut out = String::new();
+    let mut current = None;
+    for (base, _, _, name, labels, value) in rows {
+        if current.as_ref() != Some(&base) {
+            if let Some((_, kind, help)) = DESCRIPTIONS.iter().find(|(n, _, _)| *n == base) {
+                writeln!(out, "# HELP {} {}", base, help)?;
+                writeln!(out, "# TYPE {} {}", base, kind)?;
+            }
+            current = Some(base);
+        }
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, value)?;
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, value)?;
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::{distr::Alphanumeric, Rng};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{
+    catalog::CatalogData,
+    db::{Db, MIGRATOR},
+    storage::Storage,
+};
+
+/// 1 つの確認にかける時間の上限
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    /// 設定が無いなど、確認する対象が無い
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Check {
+    pub status: CheckStatus,
+    pub elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub detail: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn skipped(reason: &str) -> Self {
+        Self {
+            status: CheckStatus::Skipped,
+            elapsed_ms: 0,
+            detail: json!({ "reason": reason }),
+            error: None,
+        }
+    }
+}
+
+/// 時間を測りながら確認する (`CHECK_TIMEOUT` を過ぎたら失敗)
+pub async fn run(check: impl Future<Output = Result<Value>>) -> Check {
+    let start = Instant::now();
+    let res = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out after {:?}", CHECK_TIMEOUT)));
+    let elapsed_ms = start.elapsed().as_millis();
+    match res {
+        Ok(detail) => Check {
+            status: CheckStatus::Ok,
+            elapsed_ms,
+            detail,
+            error: None,
+        },
+        Err(e) => Check {
+            status: CheckStatus::Failed,
+            elapsed_ms,
+            detail: Value::Null,
+            error: Some(format!("{:#}", e)),
+        },
+    }
+}
+
+/// 読み取り用・書き込み用の両方のプールで問い合わせられるか
+pub async fn check_database(db: &Db) -> Result<Value> {
+    sqlx::query("select 1").execute(&db.reader).await?;
+    sqlx::query("select 1").execute(&db.writer).await?;
+    Ok(json!({ "journal_mode": format!("{:?}", db.journal_mode).to_lowercase() }))
+}
+
+/// バイナリの知っているマイグレーションがすべて適用済みか
+///
+/// DB の方が新しい (新しいイメージの配備前にマイグレーションした) のは問題にしない
+pub async fn check_migrations(pool: &SqlitePool) -> Result<Value> {
+    let applied: Vec<i64> =
+        sqlx::query_scalar(r"select version from _sqlx_migrations where success order by version")
+            .fetch_all(pool)
+            .await?;
+    let pending = MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| m.version)
+        .filter(|v| !applied.contains(v))
+        .collect::<Vec<_>>();
+    let expected = MIGRATOR.iter().map(|m| m.version).max();
+    if !pending.is_empty() {
+        return Err(anyhow!("Pending migrations: {:?}", pending));
+    }
+    Ok(json!({
+        "applied": applied.last(),
+        "expected": expected,
+    }))
+}
+
+/// 一時的なファイルを置いて消せるか
+pub async fn check_storage(storage: &Storage) -> Result<Value> {
+    let id = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+    let key = format!(".readiness/{}", id);
+    storage.put(&key, b"ok".to_vec(), "text/plain").await?;
+    storage.delete(&key).await?;
+    Ok(Value::Null)
+}
+
+/// 楽曲・譜面が読み込めていて、空ではないか
+pub fn check_catalog(catalog: &CatalogData) -> Result<Value> {
+    if catalog.charts().is_empty() {
+        return Err(anyhow!("No charts in catalog"));
+    }
+    Ok(json!({
+        "version": catalog.version,
+        "songs": catalog.songs().len(),
+        "charts": catalog.charts().len(),
+    }))
+}
+
+/// 全体の結果 (1 つでも失敗があれば 503)
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReadinessReport {
+    pub status: CheckStatus,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl ReadinessReport {
+    pub fn new(checks: impl IntoIterator<Item = (&'static str, Check)>) -> Self {
+        let checks = checks.into_iter().collect::<BTreeMap<_, _>>();
+        let status = if checks.values().any(|c| c.status == CheckStatus::Failed) {
+            CheckStatus::Failed
+        } else {
+            CheckStatus::Ok
+        };
+        Self { status, checks }
+    }
+}
+
+impl IntoResponse for ReadinessReport {
+    fn into_response(self) -> Response {
+        let code = match self.status {
+            CheckStatus::Failed => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::OK,
+        };
+        (code, Json(self)).into_response()
+    }
+}
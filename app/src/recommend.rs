@@ -0,0 +1,323 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{ClearKind, ClearRank};
+
+/// フレアランクの最大値 (EX)
+pub const MAX_FLARE_RANK: i64 = 10;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct RecommendFilter {
+    pub level: Option<i64>,
+    pub min_level: Option<i64>,
+    pub max_level: Option<i64>,
+    pub difficulty: Option<i64>,
+    /// 目標のクリアランプ (`FC` など)。省略すると各譜面の一つ上
+    pub lamp_target: Option<String>,
+    /// 目標のフレアランク。省略すると各譜面の一つ上
+    pub flare_target: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Milestone {
+    pub chart: i64,
+    pub title: String,
+    pub difficulty: i64,
+    pub level: i64,
+    pub score: Option<i64>,
+    pub current: String,
+    /// ランクは一つ上、クリアランプとフレアは目標
+    pub next: String,
+    /// ランクはスコア差、クリアランプとフレアは目標までの段階数
+    pub gap: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Recommendations {
+    pub rank: Vec<Milestone>,
+    pub lamp: Vec<Milestone>,
+    pub flare: Vec<Milestone>,
+}
+
+/// 差の小さい順、同じならスコアの高い順に並べて先頭 `limit` 件を残す
+///
+/// クリアランプとフレアは段階数が同じ譜面が多いので、実際の並びはほぼスコアの高い順になる
+/// (スコアが高いほど判定やゲージに余裕があり、次の段階に近いとみなす)
+fn closest(mut ms: Vec<Milestone>, limit: usize) -> Vec<Milestone> {
+    ms.sort_by_key(|m| (m.gap, -m.score.unwrap_or(0), m.chart));
+    ms.truncate(limit);
+    ms
+}
+
+pub async fn recommend(
+    pool: &SqlitePool,
+    user_id: i64,
+    filter: &RecommendFilter,
+) -> Result<Recommendations> {
+    let lamp_target = filter
+        .lamp_target
+        .as_deref()
+        .map(str::parse::<ClearKind>)
+        .transpose()?;
+    let flare_target = filter.flare_target.map(|f| f.min(MAX_FLARE_RANK));
+
+    let bests = sqlx::query!(
+        r"select
+            chart.id as chart,
+            song.name as title,
+            chart.difficulty,
+            chart.level,
+            bs.score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank
+        from
+            best
+        inner join score as bs on bs.id = best.score
+        inner join chart on chart.id = best.chart
+        inner join song on song.id = chart.song
+        where
+            best.user = ?",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter(|b| {
+        filter.level.is_none_or(|l| l == b.level)
+            && filter.min_level.is_none_or(|l| l <= b.level)
+            && filter.max_level.is_none_or(|l| b.level <= l)
+            && filter.difficulty.is_none_or(|d| d == b.difficulty)
+    });
+
+    let mut rank = vec![];
+    let mut lamp = vec![];
+    let mut flare = vec![];
+    for b in bests {
+        let milestone = |current: String, next: String, gap: i64| Milestone {
+            chart: b.chart,
+            title: b.title.clone(),
+            difficulty: b.difficulty,
+            level: b.level,
+            score: b.score,
+            current,
+            next,
+            gap,
+        };
+
+        // 次のランクまでのスコア差 (未クリアの E からはスコアで測れないので除外)
+        if let (Some(score), Some(cur)) = (
+            b.score,
+            b.clear_rank
+                .as_ref()
+                .and_then(|r| r.parse::<ClearRank>().ok()),
+        ) {
+            if let Some((next, thres)) = cur
+                .next()
+                .and_then(|n| n.min_score().map(|t| (n, t)))
+                .filter(|_| cur.min_score().is_some())
+            {
+                rank.push(milestone(
+                    cur.to_string(),
+                    next.to_string(),
+                    (thres - score).max(0),
+                ));
+            }
+        }
+
+        if let Some(cur) = b
+            .clear_kind
+            .as_ref()
+            .and_then(|k| k.parse::<ClearKind>().ok())
+        {
+            // ClearKind は良いものほど小さい
+            if let Some(target) = lamp_target.or(cur.next()) {
+                let gap = cur as i64 - target as i64;
+                if gap > 0 {
+                    lamp.push(milestone(cur.to_string(), target.to_string(), gap));
+                }
+            }
+        }
+
+        let cur_flare = b.flare_rank.unwrap_or(0);
+        let target = flare_target.unwrap_or((cur_flare + 1).min(MAX_FLARE_RANK));
+        if b.score.is_some() && cur_flare < target {
+            flare.push(milestone(
+                cur_flare.to_string(),
+                target.to_string(),
+                target - cur_flare,
+            ));
+        }
+    }
+
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT);
+    Ok(Recommendations {
+        rank: closest(rank, limit),
+        lamp: closest(lamp, limit),
+        flare: closest(flare, limit),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::RequestSongData,
+        catalog::{self, CatalogData},
+        db,
+        scores::{self, RequestScoreData},
+    };
+
+    fn record(
+        title: &str,
+        difficulty: &str,
+        score: i64,
+        rank: &str,
+        clear_kind: &str,
+        flare_rank: Option<i64>,
+    ) -> RequestScoreData {
+        RequestScoreData {
+            title: title.to_owned(),
+            difficulty: difficulty.to_owned(),
+            score: Some(score),
+            ex_score: None,
+            rank: Some(rank.to_owned()),
+            clear_kind: Some(clear_kind.to_owned()),
+            flare_skill: None,
+            flare_rank,
+            played_at: None,
+        }
+    }
+
+    async fn seed() -> (SqlitePool, i64) {
+        let pool = db::test_pool().await;
+        catalog::add_songs(
+            &pool,
+            &[
+                RequestSongData {
+                    name: "PARANOiA".to_owned(),
+                    version: "1st".to_owned(),
+                    levels: [None, None, None, Some(14), Some(16)],
+                },
+                RequestSongData {
+                    name: "MAX 300".to_owned(),
+                    version: "3rd".to_owned(),
+                    levels: [None, None, None, Some(14), None],
+                },
+            ],
+        )
+        .await
+        .unwrap();
+        let user_id = sqlx::query_scalar::<_, i64>(
+            "insert into user (name, password_hash) values ('alice', '') returning id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let catalog = CatalogData::load(&pool).await.unwrap();
+        scores::update_scores(
+            &pool,
+            &catalog,
+            user_id,
+            &[
+                record("PARANOiA", "EXPERT", 940_000, "AA", "FC", Some(5)),
+                record("MAX 300", "EXPERT", 895_000, "AA-", "CLEAR", None),
+                record("PARANOiA", "CHALLENGE", 800_000, "A", "FAILED", None),
+            ],
+        )
+        .await
+        .unwrap();
+        (pool, user_id)
+    }
+
+    fn summary(ms: &[Milestone]) -> Vec<(&str, i64, &str, i64)> {
+        ms.iter()
+            .map(|m| (m.title.as_str(), m.difficulty, m.next.as_str(), m.gap))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recommends_the_next_step_closest_first() {
+        let (pool, user_id) = seed().await;
+        let res = recommend(&pool, user_id, &RecommendFilter::default())
+            .await
+            .unwrap();
+
+        // ランクは次の閾値までのスコア差
+        assert_eq!(
+            summary(&res.rank),
+            vec![
+                ("MAX 300", 3, "AA", 5_000),
+                ("PARANOiA", 3, "AA+", 10_000),
+                ("PARANOiA", 4, "A+", 50_000),
+            ]
+        );
+        // 段階数が同じならスコアの高い順
+        assert_eq!(
+            summary(&res.lamp),
+            vec![
+                ("PARANOiA", 3, "GFC", 1),
+                ("MAX 300", 3, "LIFE4", 1),
+                ("PARANOiA", 4, "ASSISTED", 1),
+            ]
+        );
+        assert_eq!(
+            summary(&res.flare),
+            vec![
+                ("PARANOiA", 3, "6", 1),
+                ("MAX 300", 3, "1", 1),
+                ("PARANOiA", 4, "1", 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn measures_gaps_to_the_given_targets() {
+        let (pool, user_id) = seed().await;
+        let filter = RecommendFilter {
+            level: Some(14),
+            lamp_target: Some("fc".to_owned()),
+            flare_target: Some(8),
+            ..Default::default()
+        };
+        let res = recommend(&pool, user_id, &filter).await.unwrap();
+
+        // 既に目標を満たしている譜面は載らない
+        assert_eq!(summary(&res.lamp), vec![("MAX 300", 3, "FC", 2)]);
+        assert_eq!(
+            summary(&res.flare),
+            vec![("PARANOiA", 3, "8", 3), ("MAX 300", 3, "8", 8)]
+        );
+        assert!(res.rank.iter().all(|m| m.level == 14));
+    }
+
+    #[tokio::test]
+    async fn filters_and_limits() {
+        let (pool, user_id) = seed().await;
+        let filter = RecommendFilter {
+            difficulty: Some(3),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let res = recommend(&pool, user_id, &filter).await.unwrap();
+        assert_eq!(summary(&res.rank), vec![("MAX 300", 3, "AA", 5_000)]);
+
+        let filter = RecommendFilter {
+            min_level: Some(15),
+            ..Default::default()
+        };
+        let res = recommend(&pool, user_id, &filter).await.unwrap();
+        assert_eq!(summary(&res.rank), vec![("PARANOiA", 4, "A+", 50_000)]);
+
+        let filter = RecommendFilter {
+            lamp_target: Some("SUPER FC".to_owned()),
+            ..Default::default()
+        };
+        assert!(recommend(&pool, user_id, &filter).await.is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::{
+    catalog::CatalogData,
+    export::{self, ExportFormat, ExportOptions},
+    Difficulty,
+};
+
+/// ダンプの 1 記録分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpEntry {
+    pub score: Option<i64>,
+    /// `ex_score` のカラムが無い (スキーマ 1 の) ダンプでは None
+    pub ex_score: Option<i64>,
+    pub clear_rank: Option<String>,
+    pub clear_kind: Option<String>,
+    pub flare_rank: Option<i64>,
+    pub flare_skill: Option<i64>,
+}
+
+impl DumpEntry {
+    /// 既存の記録と同じか (`ex_score` の無いダンプでは `ex_score` を比べない)
+    pub fn matches(&self, existing: &DumpEntry) -> bool {
+        let ex_score = self.ex_score.is_none() || self.ex_score == existing.ex_score;
+        ex_score
+            && self.score == existing.score
+            && self.clear_rank == existing.clear_rank
+            && self.clear_kind == existing.clear_kind
+            && self.flare_rank == existing.flare_rank
+            && self.flare_skill == existing.flare_skill
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpChart {
+    pub title: String,
+    pub difficulty: i64,
+    /// (記録, 登録日時) の古い順
+    pub entries: Vec<(DumpEntry, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpBest {
+    pub title: String,
+    pub difficulty: i64,
+    pub entry: DumpEntry,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestoreReport {
+    pub inserted: usize,
+    pub duplicates: usize,
+    pub bests_rebuilt: usize,
+    pub unmatched: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+fn parse_int(s: &str) -> Result<Option<i64>> {
+    Ok(if s.is_empty() { None } else { Some(s.parse()?) })
+}
+
+fn parse_text(s: &str) -> Option<String> {
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+/// ファイル名 (`scores.tsv.gz` など) から形式を判断して表を読み込む
+fn read_table(raw: &[u8], file_name: &str) -> Result<(Vec<String>, Vec<csv::StringRecord>)> {
+    let (_, options) = ExportOptions::parse_file_name(file_name)?;
+    let delimiter = match options.format {
+        ExportFormat::Tsv => b'\t',
+        ExportFormat::Csv => b',',
+        ExportFormat::Jsonl => return Err(anyhow!("Cannot restore from {}", file_name)),
+    };
+    let raw = export::decompress(raw, options.compression)?;
+
+    let mut r = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(raw.as_slice());
+    let header = r.headers()?.iter().map(|h| h.to_owned()).collect();
+    let rows = r.records().collect::<Result<Vec<_>, _>>()?;
+    Ok((header, rows))
+}
+
+fn column<'a>(header: &[String], row: &'a csv::StringRecord, name: &str) -> &'a str {
+    header
+        .iter()
+        .position(|h| h == name)
+        .and_then(|i| row.get(i))
+        .unwrap_or_default()
+}
+
+fn required<'a>(header: &[String], row: &'a csv::StringRecord, name: &str) -> Result<&'a str> {
+    if header.iter().any(|h| h == name) {
+        Ok(column(header, row, name))
+    } else {
+        Err(anyhow!("Missing column: {}", name))
+    }
+}
+
+/// `scores` ダンプ (各カラムが `group_concat` でカンマ区切りになったもの) を読む
+pub fn parse_scores(raw: &[u8], file_name: &str) -> Result<Vec<DumpChart>> {
+    let (header, rows) = read_table(raw, file_name)?;
+
+    let mut res = vec![];
+    for row in rows {
+        let dates = required(&header, &row, "updated_at")?
+            .split(',')
+            .collect::<Vec<_>>();
+        let values = |name: &str| -> Vec<&str> {
+            let v = column(&header, &row, name);
+            if v.is_empty() {
+                vec![""; dates.len()]
+            } else {
+                v.split(',').collect()
+            }
+        };
+        let (scores, ex_scores, ranks, kinds, flare_ranks, flare_skills) = (
+            values("score"),
+            values("ex_score"),
+            values("clear_rank"),
+            values("clear_kind"),
+            values("flare_rank"),
+            values("flare_skill"),
+        );
+        if [
+            &scores,
+            &ex_scores,
+            &ranks,
+            &kinds,
+            &flare_ranks,
+            &flare_skills,
+        ]
+        .iter()
+        .any(|v| v.len() != dates.len())
+        {
+            return Err(anyhow!(
+                "Inconsistent history length: {}",
+                required(&header, &row, "title")?
+            ));
+        }
+
+        let mut entries = vec![];
+        for i in 0..dates.len() {
+            entries.push((
+                DumpEntry {
+                    score: parse_int(scores[i])?,
+                    ex_score: parse_int(ex_scores[i])?,
+                    clear_rank: parse_text(ranks[i]),
+                    clear_kind: parse_text(kinds[i]),
+                    flare_rank: parse_int(flare_ranks[i])?,
+                    flare_skill: parse_int(flare_skills[i])?,
+                },
+                dates[i].to_owned(),
+            ));
+        }
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+
+        res.push(DumpChart {
+            title: required(&header, &row, "title")?.to_owned(),
+            difficulty: required(&header, &row, "difficulty")?.parse()?,
+            entries,
+        });
+    }
+
+    Ok(res)
+}
+
+/// `bests` ダンプを読む (LOCKED の行は除く)
+pub fn parse_bests(raw: &[u8], file_name: &str) -> Result<Vec<DumpBest>> {
+    let (header, rows) = read_table(raw, file_name)?;
+
+    let mut res = vec![];
+    for row in rows {
+        if column(&header, &row, "clear_kind") == "LOCKED" {
+            continue;
+        }
+        res.push(DumpBest {
+            title: required(&header, &row, "title")?.to_owned(),
+            difficulty: required(&header, &row, "difficulty")?.parse()?,
+            entry: DumpEntry {
+                score: parse_int(column(&header, &row, "score"))?,
+                ex_score: parse_int(column(&header, &row, "ex_score"))?,
+                clear_rank: parse_text(column(&header, &row, "clear_rank")),
+                clear_kind: parse_text(column(&header, &row, "clear_kind")),
+                flare_rank: parse_int(column(&header, &row, "flare_rank"))?,
+                flare_skill: parse_int(column(&header, &row, "flare_skill"))?,
+            },
+        });
+    }
+
+    Ok(res)
+}
+
+fn chart_label(title: &str, difficulty: i64) -> String {
+    match Difficulty::ALL.get(difficulty as usize) {
+        Some(d) => format!("{} ({})", title, d),
+        None => format!("{} ({})", title, difficulty),
+    }
+}
+
+/// ダンプの内容を `score` に戻し、影響のあった譜面の `best` を作り直す
+pub async fn restore(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    history: &[DumpChart],
+    bests: &[DumpBest],
+    dry_run: bool,
+) -> Result<RestoreReport> {
+    let mut report = RestoreReport::default();
+
+    let find_chart = |title: &str, difficulty: i64| {
+        let dif = *Difficulty::ALL.get(difficulty as usize)?;
+        catalog.find_chart(title, dif).map(|c| c.id)
+    };
+
+    let mut existing: HashMap<(i64, String), Vec<DumpEntry>> = HashMap::new();
+    for r in sqlx::query!(
+        r"select chart, score, ex_score, clear_rank, clear_kind, flare_rank, flare_skill, created_at
+        from score where user = ?",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        existing
+            .entry((r.chart, r.created_at))
+            .or_default()
+            .push(DumpEntry {
+                score: r.score,
+                ex_score: r.ex_score,
+                clear_rank: r.clear_rank,
+                clear_kind: r.clear_kind,
+                flare_rank: r.flare_rank,
+                flare_skill: r.flare_skill,
+            });
+    }
+
+    let mut inserts: Vec<(i64, &DumpEntry, String)> = vec![];
+    let mut latest: HashMap<i64, &DumpEntry> = HashMap::new();
+    for c in history {
+        let label = chart_label(&c.title, c.difficulty);
+        let Some(chart_id) = find_chart(&c.title, c.difficulty) else {
+            report.unmatched.push(label);
+            continue;
+        };
+        for (entry, created_at) in &c.entries {
+            match existing.get(&(chart_id, created_at.clone())) {
+                Some(es) if es.iter().any(|e| entry.matches(e)) => report.duplicates += 1,
+                Some(_) => report.conflicts.push(format!(
+                    "{} at {}: differs from existing record",
+                    label, created_at
+                )),
+                None => inserts.push((chart_id, entry, created_at.clone())),
+            }
+        }
+        if let Some((entry, _)) = c.entries.last() {
+            latest.insert(chart_id, entry);
+        }
+    }
+
+    // 履歴のない自己ベストは復元時刻で登録する
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    for b in bests {
+        let label = chart_label(&b.title, b.difficulty);
+        let Some(chart_id) = find_chart(&b.title, b.difficulty) else {
+            report.unmatched.push(label);
+            continue;
+        };
+        match latest.get(&chart_id) {
+            Some(e) if b.entry.matches(e) => {}
+            Some(_) => report
+                .conflicts
+                .push(format!("{}: best differs from latest history entry", label)),
+            None => {
+                report.conflicts.push(format!(
+                    "{}: best has no history, restored as of now",
+                    label
+                ));
+                inserts.push((chart_id, &b.entry, now.clone()));
+            }
+        }
+    }
+
+    report.inserted = inserts.len();
+    let affected = inserts.iter().map(|i| i.0).collect::<HashSet<_>>();
+    report.bests_rebuilt = affected.len();
+    if dry_run || inserts.is_empty() {
+        return Ok(report);
+    }
+
+    let ingested_at = Utc::now().timestamp();
+    let inserts = inserts
+        .into_iter()
+        .map(|(chart_id, e, created_at)| {
+            let played_at = DateTime::parse_from_rfc3339(&created_at)
+                .map_err(|_| anyhow!("Invalid date in dump: {}", created_at))?
+                .timestamp();
+            Ok((chart_id, e, created_at, played_at))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut tx = pool.begin().await?;
+
+    const BIND_LIMIT: usize = 32766;
+    for chunk in inserts.chunks(BIND_LIMIT / 11) {
+        let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "insert into score (user, chart, score, ex_score, clear_rank, clear_kind, flare_rank, flare_skill, created_at, played_at, ingested_at) ",
+        );
+        qb.push_values(chunk, |mut b, (chart_id, e, created_at, played_at)| {
+            b.push_bind(user_id)
+                .push_bind(chart_id)
+                .push_bind(e.score)
+                .push_bind(e.ex_score)
+                .push_bind(&e.clear_rank)
+                .push_bind(&e.clear_kind)
+                .push_bind(e.flare_rank)
+                .push_bind(e.flare_skill)
+                .push_bind(created_at)
+                .push_bind(played_at)
+                .push_bind(ingested_at);
+        });
+        qb.build().execute(&mut *tx).await?;
+    }
+
+    for chart_id in affected {
+        let score_id = sqlx::query!(
+            r"select id from score where user = ? and chart = ?
+            order by played_at desc, id desc limit 1",
+            user_id,
+            chart_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+        let updated = sqlx::query!(
+            r"update best set score = ? where user = ? and chart = ?",
+            score_id,
+            user_id,
+            chart_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            sqlx::query!(
+                r"insert into best (user, chart, score) values (?, ?, ?)",
+                user_id,
+                chart_id,
+                score_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(score: Option<i64>, clear_kind: Option<&str>) -> DumpEntry {
+        DumpEntry {
+            score,
+            ex_score: None,
+            clear_rank: None,
+            clear_kind: clear_kind.map(str::to_owned),
+            flare_rank: None,
+            flare_skill: None,
+        }
+    }
+
+    #[test]
+    fn parse_scores_reads_blank_cells_as_null() {
+        let raw = "id\ttitle\tdifficulty\tlevel\tscore\tclear_rank\tclear_kind\tflare_rank\tflare_skill\tupdated_at\tex_score\n\
+            4\tPARANOiA\t3\t14\t,900000\t,AA\tFAILED,CLEAR\t\t\t2024-01-02T00:00:00Z,2024-01-01T00:00:00Z\t,1100\n";
+        let charts = parse_scores(raw.as_bytes(), "scores.tsv").unwrap();
+        assert_eq!(charts.len(), 1);
+        assert_eq!(charts[0].title, "PARANOiA");
+        assert_eq!(charts[0].difficulty, 3);
+
+        // 登録日時の古い順に並べ直す
+        let entries = &charts[0].entries;
+        assert_eq!(entries[0].1, "2024-01-01T00:00:00Z");
+        assert_eq!(
+            entries[0].0,
+            DumpEntry {
+                ex_score: Some(1100),
+                clear_rank: Some("AA".to_owned()),
+                ..entry(Some(900_000), Some("CLEAR"))
+            }
+        );
+        // 空のカラム (flare_rank など) はすべての記録で null
+        assert_eq!(entries[1].0, entry(None, Some("FAILED")));
+    }
+
+    #[test]
+    fn parse_scores_ignores_unknown_and_missing_columns() {
+        // ex_score の無いスキーマ 1 のダンプに、知らないカラムが付いている
+        let raw = "title,difficulty,extra,score,clear_kind,updated_at\n\
+            PARANOiA,3,x,\"800000,900000\",\"CLEAR,FC\",\"2024-01-01T00:00:00Z,2024-01-02T00:00:00Z\"\n";
+        let charts = parse_scores(raw.as_bytes(), "scores.csv").unwrap();
+        let entries = charts[0]
+            .entries
+            .iter()
+            .map(|(e, _)| e.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                entry(Some(800_000), Some("CLEAR")),
+                entry(Some(900_000), Some("FC"))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_scores_rejects_broken_rows() {
+        let uneven = "title\tdifficulty\tscore\tupdated_at\n\
+            PARANOiA\t3\t800000\t2024-01-01T00:00:00Z,2024-01-02T00:00:00Z\n";
+        assert!(parse_scores(uneven.as_bytes(), "scores.tsv").is_err());
+
+        let no_dates = "title\tdifficulty\tscore\nPARANOiA\t3\t800000\n";
+        assert!(parse_scores(no_dates.as_bytes(), "scores.tsv").is_err());
+
+        let bad_number = "title\tdifficulty\tscore\tupdated_at\n\
+            PARANOiA\t3\tabc\t2024-01-01T00:00:00Z\n";
+        assert!(parse_scores(bad_number.as_bytes(), "scores.tsv").is_err());
+
+        assert!(parse_scores(b"", "scores.jsonl").is_err());
+    }
+
+    #[test]
+    fn parse_bests_skips_locked_charts() {
+        let raw = "id\ttitle\tdifficulty\tlevel\tscore\tclear_rank\tclear_kind\tflare_rank\tflare_skill\tex_score\n\
+            4\tPARANOiA\t3\t14\t900000\tAA\tFC\t\t\t\n\
+            5\tPARANOiA\t4\t16\t\t\tLOCKED\t\t\t\n";
+        let bests = parse_bests(raw.as_bytes(), "bests.tsv").unwrap();
+        assert_eq!(bests.len(), 1);
+        assert_eq!(bests[0].difficulty, 3);
+        assert_eq!(
+            bests[0].entry,
+            DumpEntry {
+                clear_rank: Some("AA".to_owned()),
+                ..entry(Some(900_000), Some("FC"))
+            }
+        );
+    }
+
+    #[test]
+    fn matches_ignores_ex_score_missing_from_the_dump() {
+        let existing = DumpEntry {
+            ex_score: Some(1100),
+            ..entry(Some(900_000), Some("FC"))
+        };
+        assert!(entry(Some(900_000), Some("FC")).matches(&existing));
+        assert!(!DumpEntry {
+            ex_score: Some(1000),
+            ..entry(Some(900_000), Some("FC"))
+        }
+        .matches(&existing));
+        assert!(!entry(Some(900_001), Some("FC")).matches(&existing));
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, QueryBuilder, Sqlite, SqlitePool};
+use utoipa::ToSchema;
+
+use crate::{catalog::CatalogData, ClearKind, ClearRank, Difficulty};
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct RequestScoreData {
+    pub title: String,
+    pub difficulty: String,
+    pub score: Option<i64>,
+    pub ex_score: Option<i64>,
+    pub rank: Option<String>,
+    pub clear_kind: Option<String>,
+    pub flare_skill: Option<i64>,
+    pub flare_rank: Option<i64>,
+    /// プレイ日時 (RFC 3339, 無ければ登録時刻)
+    pub played_at: Option<DateTime<Utc>>,
+}
+
+/// 1 つの記録の値
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ScoreValues {
+    pub score: Option<i64>,
+    pub ex_score: Option<i64>,
+    pub clear_rank: Option<String>,
+    pub clear_kind: Option<String>,
+    pub flare_rank: Option<i64>,
+    pub flare_skill: Option<i64>,
+}
+
+impl From<&RequestScoreData> for ScoreValues {
+    fn from(d: &RequestScoreData) -> Self {
+        Self {
+            score: d.score,
+            ex_score: d.ex_score,
+            clear_rank: d.rank.clone(),
+            clear_kind: d.clear_kind.clone(),
+            flare_rank: d.flare_rank,
+            flare_skill: d.flare_skill,
+        }
+    }
+}
+
+impl ScoreValues {
+    fn clear_rank(&self) -> Option<ClearRank> {
+        self.clear_rank.as_ref().and_then(|r| r.parse().ok())
+    }
+
+    fn clear_kind(&self) -> Option<ClearKind> {
+        self.clear_kind.as_ref().and_then(|k| k.parse().ok())
+    }
+
+    /// `previous` より良くなった項目 (`previous` が None なら値のある項目)
+    pub fn improved_fields(&self, previous: Option<&ScoreValues>) -> Vec<ScoreField> {
+        let Some(prev) = previous else {
+            return [
+                (ScoreField::Score, self.score.is_some()),
+                (ScoreField::ExScore, self.ex_score.is_some()),
+                (ScoreField::ClearRank, self.clear_rank.is_some()),
+                (ScoreField::ClearKind, self.clear_kind.is_some()),
+                (ScoreField::FlareRank, self.flare_rank.is_some()),
+                (ScoreField::FlareSkill, self.flare_skill.is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(f, b)| b.then_some(f))
+            .collect();
+        };
+
+        // ランク・クリア種別は値が小さいほど良い
+        let rank = |v: &ScoreValues| v.clear_rank().map(|r| r as i64).unwrap_or(i64::MAX);
+        let kind = |v: &ScoreValues| v.clear_kind().map(|k| k as i64).unwrap_or(i64::MAX);
+        [
+            (
+                ScoreField::Score,
+                prev.score.unwrap_or(-1) < self.score.unwrap_or(-1),
+            ),
+            (
+                ScoreField::ExScore,
+                prev.ex_score.unwrap_or(-1) < self.ex_score.unwrap_or(-1),
+            ),
+            (ScoreField::ClearRank, rank(prev) > rank(self)),
+            (ScoreField::ClearKind, kind(prev) > kind(self)),
+            (
+                ScoreField::FlareRank,
+                prev.flare_rank.unwrap_or(-1) < self.flare_rank.unwrap_or(-1),
+            ),
+            (
+                ScoreField::FlareSkill,
+                prev.flare_skill.unwrap_or(-1) < self.flare_skill.unwrap_or(-1),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(f, b)| b.then_some(f))
+        .collect()
+    }
+}
+
+/// 記録の項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreField {
+    Score,
+    ExScore,
+    ClearRank,
+    ClearKind,
+    FlareRank,
+    FlareSkill,
+}
+
+/// 自己ベストを更新した譜面 (同じ譜面を何度も更新した場合は最初と最後の値)
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Improvement {
+    pub chart_id: i64,
+    pub title: String,
+    pub difficulty: String,
+    pub level: i64,
+    /// 更新前の自己ベスト (初めての記録なら None)
+    pub previous: Option<ScoreValues>,
+    pub current: ScoreValues,
+    /// `previous` から良くなった項目
+    pub improved: Vec<ScoreField>,
+}
+
+/// 自己ベスト更新の集計
+///
+/// ランク・クリア種別は新しく付いたものごとに数える (GFC から MFC になれば `new_mfc` だけ)
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ImprovementSummary {
+    /// 更新した譜面数
+    pub charts: usize,
+    /// 初めて記録した譜面数
+    pub first_plays: usize,
+    pub score_ups: usize,
+    pub flare_ups: usize,
+    pub new_aaa: usize,
+    pub new_mfc: usize,
+    pub new_pfc: usize,
+    pub new_gfc: usize,
+    pub new_fc: usize,
+    /// 未クリア (記録なし・FAILED) からクリアした譜面数
+    pub new_clears: usize,
+}
+
+impl ImprovementSummary {
+    pub fn new(improvements: &[Improvement]) -> Self {
+        let mut res = Self {
+            charts: improvements.len(),
+            ..Default::default()
+        };
+        for imp in improvements {
+            if imp.previous.is_none() {
+                res.first_plays += 1;
+            }
+            if imp.improved.contains(&ScoreField::Score) && imp.previous.is_some() {
+                res.score_ups += 1;
+            }
+            if imp.improved.contains(&ScoreField::FlareRank) && imp.previous.is_some() {
+                res.flare_ups += 1;
+            }
+            if imp.improved.contains(&ScoreField::ClearRank)
+                && matches!(imp.current.clear_rank(), Some(ClearRank::AAA))
+            {
+                res.new_aaa += 1;
+            }
+            if imp.improved.contains(&ScoreField::ClearKind) {
+                match imp.current.clear_kind() {
+                    Some(ClearKind::MFC) => res.new_mfc += 1,
+                    Some(ClearKind::PFC) => res.new_pfc += 1,
+                    Some(ClearKind::GFC) => res.new_gfc += 1,
+                    Some(ClearKind::FC) => res.new_fc += 1,
+                    _ => {}
+                }
+            }
+            let cleared = |v: Option<&ScoreValues>| {
+                v.and_then(|v| v.clear_kind())
+                    .is_some_and(|k| (k as i64) < ClearKind::Failed as i64)
+            };
+            if !cleared(imp.previous.as_ref()) && cleared(Some(&imp.current)) {
+                res.new_clears += 1;
+            }
+        }
+        res
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOutcome {
+    pub updated: usize,
+    pub errors: Vec<String>,
+    /// 楽曲・譜面が見つからなかった記録 (`title (DIFFICULTY)`)
+    pub unmatched: Vec<String>,
+    pub improvements: Vec<Improvement>,
+}
+
+/// 自己ベストを更新した記録だけを `score` に登録し、`best` を更新する
+///
+/// 同じ譜面の記録が複数ある場合は日時の古い順に比較する
+#[tracing::instrument(skip_all, fields(records = records.len()))]
+pub async fn update_scores(
+    pool: &SqlitePool,
+    catalog: &CatalogData,
+    user_id: i64,
+    records: &[RequestScoreData],
+) -> Result<UpdateOutcome> {
+    let mut res = UpdateOutcome::default();
+
+    // 自己ベスト情報取得
+    let mut cur_bests = sqlx::query!(
+        r"select
+            best.chart,
+            bs.score,
+            bs.ex_score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank,
+            bs.flare_skill
+        from
+            best
+        inner join score as bs on best.score = bs.id
+        where
+            best.user = ?",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        (
+            r.chart,
+            ScoreValues {
+                score: r.score,
+                ex_score: r.ex_score,
+                clear_rank: r.clear_rank,
+                clear_kind: r.clear_kind,
+                flare_rank: r.flare_rank,
+                flare_skill: r.flare_skill,
+            },
+        )
+    })
+    .collect::<HashMap<_, _>>();
+
+    let now = Utc::now();
+    let mut order = (0..records.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| records[i].played_at.unwrap_or(now));
+
+    // 自己ベストを更新したものだけに絞る
+    struct NewRecord {
+        chart_id: i64,
+        req_index: usize,
+    }
+    let mut new_records = vec![];
+    let mut improved: HashMap<i64, usize> = HashMap::new();
+    for i in order {
+        let score = &records[i];
+        let Ok(dif) = score.difficulty.parse::<Difficulty>() else {
+            res.errors.push(format!(
+                "[{}] Unknown difficulty: {}",
+                score.title, score.difficulty
+            ));
+            continue;
+        };
+
+        let Some(chart) = catalog.find_chart(&score.title, dif) else {
+            let label = format!("{} ({})", score.title, dif);
+            res.errors.push(format!("Unknown song/chart: {}", label));
+            if !res.unmatched.contains(&label) {
+                res.unmatched.push(label);
+            }
+            continue;
+        };
+
+        // フレアスキルはフレアランクとレベルから決まるので、既存の自己ベストとは比較しない
+        let values = ScoreValues::from(score);
+        let best = cur_bests.get(&chart.id);
+        let fields = values.improved_fields(best);
+        let update = match best {
+            Some(_) => fields.iter().any(|f| *f != ScoreField::FlareSkill),
+            None => !fields.is_empty(),
+        };
+
+        if update {
+            new_records.push(NewRecord {
+                chart_id: chart.id,
+                req_index: i,
+            });
+            match improved.get(&chart.id) {
+                Some(&n) => res.improvements[n].current = values.clone(),
+                None => {
+                    improved.insert(chart.id, res.improvements.len());
+                    res.improvements.push(Improvement {
+                        chart_id: chart.id,
+                        title: chart.title.clone(),
+                        difficulty: dif.to_string(),
+                        level: chart.level,
+                        previous: best.cloned(),
+                        current: values.clone(),
+                        improved: vec![],
+                    });
+                }
+            }
+            // 同じ譜面の後続の記録はこの記録と比較する
+            cur_bests.insert(chart.id, values);
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // 新規スコア情報登録
+    #[derive(FromRow)]
+    struct NewRecordId {
+        id: i64,
+        chart: i64,
+    }
+    let mut new_records_ids = vec![];
+    const BIND_LIMIT: usize = 32766;
+    for i in (0..new_records.len()).step_by(BIND_LIMIT / 11) {
+        let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+        "insert into score (user, chart, score, ex_score, clear_rank, clear_kind, flare_rank, flare_skill, created_at, played_at, ingested_at) "
+        );
+        qb.push_values(
+            &new_records[i..(i + BIND_LIMIT / 11).min(new_records.len())],
+            |mut b, r| {
+                let rq = &records[r.req_index];
+                let played_at = rq.played_at.unwrap_or(now);
+                b.push_bind(user_id)
+                    .push_bind(r.chart_id)
+                    .push_bind(rq.score)
+                    .push_bind(rq.ex_score)
+                    .push_bind(&rq.rank)
+                    .push_bind(&rq.clear_kind)
+                    .push_bind(rq.flare_rank)
+                    .push_bind(rq.flare_skill)
+                    .push_bind(played_at.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                    .push_bind(played_at.timestamp())
+                    .push_bind(now.timestamp());
+            },
+        );
+        qb.push(" returning id, chart");
+
+        let query = qb.build_query_as::<NewRecordId>();
+        new_records_ids.extend(query.fetch_all(&mut *tx).await?);
+    }
+    // 同じ譜面に複数登録した場合は最後のものが自己ベスト
+    new_records_ids.sort_by_key(|nr| nr.id);
+    let new_records_ids = new_records_ids
+        .into_iter()
+        .map(|nr| (nr.chart, nr.id))
+        .collect::<HashMap<_, _>>();
+
+    // 自己ベスト登録・更新 (同時に同じ譜面を登録しても 1 行にまとまる)
+    let bests = new_records_ids.into_iter().collect::<Vec<_>>();
+    for chunk in bests.chunks(BIND_LIMIT / 3) {
+        let mut qb: QueryBuilder<Sqlite> =
+            QueryBuilder::new("insert into best (user, chart, score) ");
+        qb.push_values(chunk, |mut b, (chart_id, score_id)| {
+            b.push_bind(user_id).push_bind(chart_id).push_bind(score_id);
+        });
+        qb.push(" on conflict (user, chart) do update set score = excluded.score");
+        qb.build().execute(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
+
+    for imp in &mut res.improvements {
+        imp.improved = imp.current.improved_fields(imp.previous.as_ref());
+    }
+    res.updated = new_records.len();
+    Ok(res)
+}
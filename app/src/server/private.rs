@@ -0,0 +1,179 @@
+use anyhow::anyhow;
+use axum::{
+    extract::{FromRef, State},
+    http::header,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use sqlx::SqlitePool;
+use utoipa::OpenApi;
+
+use crate::{
+    api::{AddSongsRequest, AddSongsResponse, AddUserRequest, ErrorResponses},
+    backup::{self, BackupInfo},
+    catalog::{self, CatalogData},
+    db::Db,
+    metrics::{self, Metrics},
+    readiness::{self, Check, ReadinessReport},
+    storage::Storage,
+    telemetry, ApiResult, Conflict,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    db: Db,
+    /// バックアップの保存先 (BACKUP_STORAGE が無ければ None)
+    backup_storage: Option<Storage>,
+    metrics: Metrics,
+}
+
+impl AppState {
+    pub fn new(db: Db, backup_storage: Option<Storage>, metrics: Metrics) -> Self {
+        Self {
+            db,
+            backup_storage,
+            metrics,
+        }
+    }
+}
+
+/// 書き込み用のプール (private の API はほとんどが書き込み)
+impl FromRef<AppState> for SqlitePool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.writer.clone()
+    }
+}
+
+#[utoipa::path(
+    post, path = "/api/private/add_user", request_body = AddUserRequest,
+    responses((status = 200))
+)]
+async fn add_user(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<AddUserRequest>,
+) -> ApiResult<()> {
+    let hash = bcrypt::hash(&req.password, 8)?;
+
+    let res = sqlx::query!(
+        r"insert into user (name, password_hash) values (?, ?)",
+        req.user,
+        hash
+    )
+    .execute(&pool)
+    .await;
+    match res {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.as_database_error()
+                .is_some_and(|e| e.is_unique_violation()) =>
+        {
+            Err(Conflict(format!("User already exists: {}", req.user)).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[utoipa::path(
+    post, path = "/api/private/add_songs", request_body = AddSongsRequest,
+    responses((status = 200, body = AddSongsResponse))
+)]
+async fn add_songs(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<AddSongsRequest>,
+) -> ApiResult<Json<AddSongsResponse>> {
+    Ok(Json(catalog::add_songs(&pool, &req.songs).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/private/backup",
+    responses((status = 200, body = BackupInfo))
+)]
+async fn create_backup(State(state): State<AppState>) -> ApiResult<Json<BackupInfo>> {
+    let storage = state
+        .backup_storage
+        .as_ref()
+        .ok_or_else(|| anyhow!("BACKUP_STORAGE is not set"))?;
+    let keep = match std::env::var("BACKUP_KEEP") {
+        Ok(v) => v.parse()?,
+        Err(_) => 7,
+    };
+
+    Ok(Json(
+        backup::create_backup(&state.db.reader, storage, keep).await?,
+    ))
+}
+
+/// public のものも含めた全プロセスのメトリクス (Prometheus のテキスト形式)
+#[utoipa::path(
+    get, path = "/metrics",
+    responses((status = 200, description = "Prometheus text format", content_type = "text/plain"))
+)]
+async fn prometheus_metrics(State(state): State<AppState>) -> ApiResult<impl IntoResponse> {
+    state.metrics.flush(&state.db.writer).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&state.db.reader).await?,
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/api/private/health", responses((status = 200))
+)]
+async fn health() -> ApiResult<()> {
+    Ok(())
+}
+
+/// 配備したイメージが実際に処理できるか (DB・マイグレーション・バックアップの保存先・楽曲一覧)
+#[utoipa::path(
+    get, path = "/api/private/ready",
+    responses(
+        (status = 200, body = ReadinessReport),
+        (status = 503, description = "Some checks failed", body = ReadinessReport),
+    )
+)]
+async fn ready(State(state): State<AppState>) -> ReadinessReport {
+    let (database, migrations, storage, catalog) = tokio::join!(
+        readiness::run(readiness::check_database(&state.db)),
+        readiness::run(readiness::check_migrations(&state.db.reader)),
+        async {
+            match &state.backup_storage {
+                Some(storage) => readiness::run(readiness::check_storage(storage)).await,
+                None => Check::skipped("BACKUP_STORAGE is not set"),
+            }
+        },
+        readiness::run(async {
+            readiness::check_catalog(&CatalogData::load(&state.db.reader).await?)
+        }),
+    );
+    ReadinessReport::new([
+        ("database", database),
+        ("migrations", migrations),
+        ("storage", storage),
+        ("catalog", catalog),
+    ])
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "DDR score data private API"),
+    paths(add_user, add_songs, create_backup, prometheus_metrics, health, ready),
+    modifiers(&ErrorResponses)
+)]
+pub struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub fn router(state: AppState) -> Router {
+    let app = Router::new()
+        .route("/api/private/health", get(health))
+        .route("/api/private/ready", get(ready))
+        .route("/api/private/openapi.json", get(openapi))
+        .route("/api/private/add_user", post(add_user))
+        .route("/api/private/add_songs", post(add_songs))
+        .route("/api/private/backup", post(create_backup))
+        .route("/metrics", get(prometheus_metrics));
+    telemetry::instrument(app, "private", state.metrics.clone()).with_state(state)
+}
@@ -0,0 +1,641 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    extract::{FromRef, Path, Query, Request, State},
+    http::{header, Method},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use sqlx::SqlitePool;
+use tower_http::cors::{self, CorsLayer};
+use tracing::Instrument;
+use utoipa::OpenApi;
+
+use crate::{
+    api::{
+        AddGoalRequest, AddGoalResponse, AddWebhookRequest, DeleteGoalRequest,
+        DeleteWebhookRequest, DeliveriesRequest, DumpRequest, DumpStatusRequest, ErrorResponses,
+        GoalsRequest, ImportRequest, LeaderboardQuery, RecommendRequest, StatsQuery, StatsRequest,
+        TimelineRequest, UpdateScoreRequest, UpdateScoreResponse, UpdateSettingsRequest,
+        UserDataRequest, WebhooksRequest,
+    },
+    catalog::Catalog,
+    db::{Db, JournalMode},
+    dump::{self, DumpFile, PublishResult},
+    dump_job::{self, DumpJob, DumpQueue, DumpStatus},
+    export::ExportOptions,
+    goals::{self, GoalProgress},
+    import::{self, ImportReport},
+    leaderboard::{self, ChartLeaderboard, LevelSummary},
+    metrics::Metrics,
+    readiness::{self, ReadinessReport},
+    recommend::{self, Recommendations},
+    scores::{self, ImprovementSummary},
+    settings::{self, UserSettings, Visibility},
+    stats::{self, GroupStats},
+    storage::Storage,
+    telemetry,
+    timeline::{self, TimelineFilter, TimelinePoint},
+    webhook::{self, Delivery, NewWebhook, Webhook, WebhookPolicy, WebhookQueue},
+    ApiResult, Forbidden, NotFound,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    db: Db,
+    catalog: Catalog,
+    /// 公開ダンプの置き場所
+    storage: Storage,
+    dump_queue: DumpQueue,
+    webhooks: WebhookQueue,
+    metrics: Metrics,
+}
+
+impl AppState {
+    /// 楽曲一覧を読み込み、ダンプの作り直しのキューを用意する
+    pub async fn new(db: Db, storage: Storage, metrics: Metrics) -> Result<Self> {
+        let catalog = Catalog::load(db.reader.clone()).await?;
+        Ok(Self {
+            dump_queue: DumpQueue::new(db.clone(), catalog.clone(), storage.clone()),
+            webhooks: WebhookQueue::new(db.clone(), WebhookPolicy::from_env())?,
+            db,
+            catalog,
+            storage,
+            metrics,
+        })
+    }
+}
+
+/// 読み取り用のプール
+impl FromRef<AppState> for SqlitePool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.reader.clone()
+    }
+}
+
+/// 書き込み用のプール
+#[derive(Clone)]
+struct Writer(SqlitePool);
+
+impl FromRef<AppState> for Writer {
+    fn from_ref(state: &AppState) -> Self {
+        Writer(state.db.writer.clone())
+    }
+}
+
+async fn auth_user(
+    pool: SqlitePool,
+    name: impl AsRef<str>,
+    password: impl AsRef<str>,
+) -> Result<i64> {
+    let name = name.as_ref();
+    let password = password.as_ref();
+    telemetry::record_user(name);
+
+    async {
+        let user = sqlx::query!(r"select id, password_hash from user where name = ?", name)
+            .fetch_one(&pool)
+            .await?;
+
+        if bcrypt::verify(password, &user.password_hash)? {
+            Ok(user.id)
+        } else {
+            Err(anyhow!("User authentication failed"))
+        }
+    }
+    .instrument(tracing::info_span!("auth"))
+    .await
+}
+
+/// `viewer` が閲覧できる場合のみ `target` のユーザー ID を返す
+async fn viewable_user(pool: &SqlitePool, viewer: Option<i64>, target: &str) -> Result<i64> {
+    let target_id = sqlx::query!(r"select id from user where name = ?", target)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| NotFound(format!("Unknown user: {}", target)))?
+        .id;
+
+    if settings::can_view(pool, viewer, target_id).await? {
+        Ok(target_id)
+    } else {
+        Err(Forbidden(format!("Access denied: {}", target)).into())
+    }
+}
+
+#[utoipa::path(
+    post, path = "/api/update_score", request_body = UpdateScoreRequest,
+    responses((status = 200, body = UpdateScoreResponse))
+)]
+async fn update_score(
+    State(state): State<AppState>,
+    Json(req): Json<UpdateScoreRequest>,
+) -> ApiResult<Json<UpdateScoreResponse>> {
+    let pool = state.db.writer;
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    // 記録を保存してから失敗しないよう、ダンプの指定は先に確かめる
+    if req.dump {
+        dump::check_publish_options(&req.dump_options)?;
+    }
+
+    let catalog = state.catalog.current().await?;
+    let outcome = scores::update_scores(&pool, &catalog, user_id, &req.scores).await?;
+    state
+        .metrics
+        .record_sync("update_score", req.scores.len(), outcome.updated);
+    // 記録は保存済みなので、ここで失敗を返すとクライアントが送り直して二重に数えてしまう
+    if let Err(e) = state
+        .webhooks
+        .notify(user_id, &req.user, &outcome.improvements)
+        .await
+    {
+        tracing::warn!("failed to queue webhooks: {:#}", e);
+    }
+
+    let mut res = UpdateScoreResponse {
+        updated: outcome.updated,
+        errors: outcome.errors,
+        summary: ImprovementSummary::new(&outcome.improvements),
+        improvements: outcome.improvements,
+        completed_goals: vec![],
+        dump_queued: false,
+    };
+    if res.updated > 0 {
+        res.completed_goals = goals::newly_completed(&pool, user_id, &res.improvements).await?;
+        if req.dump {
+            state
+                .dump_queue
+                .enqueue(DumpJob {
+                    user_id,
+                    user: req.user,
+                    options: req.dump_options,
+                })
+                .await?;
+            res.dump_queued = true;
+        }
+    }
+    Ok(Json(res))
+}
+
+#[utoipa::path(
+    post, path = "/api/import", request_body = ImportRequest,
+    responses((status = 200, body = ImportReport))
+)]
+async fn import_scores(
+    State(state): State<AppState>,
+    Json(req): Json<ImportRequest>,
+) -> ApiResult<Json<ImportReport>> {
+    let user_id = auth_user(state.db.reader.clone(), &req.user, &req.password).await?;
+    let importer = import::importer(&req.format)?;
+    let catalog = state.catalog.current().await?;
+    let report = import::import(
+        &state.db.writer,
+        &catalog,
+        user_id,
+        importer.as_ref(),
+        req.data.as_bytes(),
+    )
+    .await?;
+    state
+        .metrics
+        .record_sync("import", report.parsed, report.updated);
+    Ok(Json(report))
+}
+
+#[utoipa::path(
+    post, path = "/api/dump_user_data", request_body = DumpRequest,
+    responses((status = 200, description = "Null when the user data is private", body = Option<PublishResult>))
+)]
+async fn dump_user_data(
+    State(state): State<AppState>,
+    Json(req): Json<DumpRequest>,
+) -> ApiResult<Json<Option<PublishResult>>> {
+    let user_id = auth_user(state.db.reader.clone(), &req.user, &req.password).await?;
+    dump::check_publish_options(&req.options)?;
+    let job = DumpJob {
+        user_id,
+        user: req.user,
+        options: req.options,
+    };
+    Ok(Json(
+        dump_job::run(&state.db, &state.catalog, &state.storage, &job, 1).await?,
+    ))
+}
+
+#[utoipa::path(
+    post, path = "/api/dump_status", request_body = DumpStatusRequest,
+    responses((status = 200, body = DumpStatus))
+)]
+async fn dump_status(
+    State(state): State<AppState>,
+    Json(req): Json<DumpStatusRequest>,
+) -> ApiResult<Json<DumpStatus>> {
+    let user_id = auth_user(state.db.reader.clone(), &req.user, &req.password).await?;
+    Ok(Json(state.dump_queue.status(user_id).await?))
+}
+
+async fn dump_response(
+    state: AppState,
+    user_id: i64,
+    file: DumpFile,
+    options: ExportOptions,
+) -> Result<impl IntoResponse> {
+    let catalog = state.catalog.current().await?;
+    // 接続が 1 つしかなければ、送り終わるまで他のリクエストを待たせないよう先に生成する
+    let body = match state.db.journal_mode {
+        JournalMode::Delete => {
+            Body::from(dump::buffer(&state.db.reader, &catalog, user_id, file, &options).await?)
+        }
+        _ => Body::from_stream(dump::stream(
+            state.db.reader,
+            catalog,
+            user_id,
+            file,
+            options.clone(),
+        )?),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, options.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}\"",
+                    options.file_name(file.stem())
+                ),
+            ),
+        ],
+        body,
+    ))
+}
+
+#[utoipa::path(
+    post, path = "/api/user_data", request_body = UserDataRequest,
+    responses((status = 200, description = "Dump file in the requested format", content_type = "application/octet-stream"))
+)]
+async fn user_data(
+    State(state): State<AppState>,
+    Json(req): Json<UserDataRequest>,
+) -> ApiResult<impl IntoResponse> {
+    let user_id = auth_user(state.db.reader.clone(), &req.user, &req.password).await?;
+    let target = req.target.as_ref().unwrap_or(&req.user);
+    let target_id = viewable_user(&state.db.reader, Some(user_id), target).await?;
+
+    Ok(dump_response(state, target_id, req.file, req.options).await?)
+}
+
+#[utoipa::path(
+    get, path = "/api/shared/{token}/{file}",
+    params(("token" = String, Path), ("file" = String, Path, description = "File name such as `bests.csv.gz`")),
+    responses((status = 200, description = "Dump file in the format given by the file name", content_type = "application/octet-stream"))
+)]
+async fn shared_data(
+    State(state): State<AppState>,
+    Path((token, file_name)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let user_id = settings::user_by_share_token(&state.db.reader, &token).await?;
+    let (file, options) = DumpFile::parse_file_name(&file_name)?;
+    Ok(dump_response(state, user_id, file, options).await?)
+}
+
+#[utoipa::path(
+    post, path = "/api/update_settings", request_body = UpdateSettingsRequest,
+    responses((status = 200, body = UserSettings))
+)]
+async fn update_settings(
+    State(state): State<AppState>,
+    Json(req): Json<UpdateSettingsRequest>,
+) -> ApiResult<Json<UserSettings>> {
+    let pool = state.db.writer.clone();
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    let settings = settings::update_settings(&pool, user_id, &req.settings).await?;
+
+    // 公開をやめたら置いてあるダンプをすぐに消す (失敗したらダンプの作り直しで消し直す)
+    if req
+        .settings
+        .visibility
+        .is_some_and(|v| v != Visibility::Public)
+    {
+        if let Err(e) = dump::unpublish(&state.storage, &req.user).await {
+            tracing::warn!("failed to unpublish dumps: {:#}", e);
+            state
+                .dump_queue
+                .enqueue(DumpJob {
+                    user_id,
+                    user: req.user.clone(),
+                    options: ExportOptions::default(),
+                })
+                .await?;
+        }
+    }
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    get, path = "/api/leaderboard/chart/{chart_id}",
+    params(("chart_id" = i64, Path), LeaderboardQuery),
+    responses((status = 200, body = ChartLeaderboard))
+)]
+async fn chart_leaderboard(
+    State(pool): State<SqlitePool>,
+    Path(chart_id): Path<i64>,
+    Query(q): Query<LeaderboardQuery>,
+) -> ApiResult<Json<ChartLeaderboard>> {
+    Ok(Json(
+        leaderboard::chart_leaderboard(&pool, chart_id, q.by).await?,
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/api/leaderboard/level/{level}",
+    params(("level" = i64, Path), LeaderboardQuery),
+    responses((status = 200, body = LevelSummary))
+)]
+async fn level_leaderboard(
+    State(pool): State<SqlitePool>,
+    Path(level): Path<i64>,
+    Query(q): Query<LeaderboardQuery>,
+) -> ApiResult<Json<LevelSummary>> {
+    Ok(Json(leaderboard::level_summary(&pool, level, q.by).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/goals", request_body = GoalsRequest,
+    responses((status = 200, body = Vec<GoalProgress>))
+)]
+async fn list_goals(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<GoalsRequest>,
+) -> ApiResult<Json<Vec<GoalProgress>>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    Ok(Json(goals::list_goals(&pool, user_id).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/goals/add", request_body = AddGoalRequest,
+    responses((status = 200, body = AddGoalResponse))
+)]
+async fn add_goal(
+    State(Writer(pool)): State<Writer>,
+    Json(req): Json<AddGoalRequest>,
+) -> ApiResult<Json<AddGoalResponse>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    let id = goals::add_goal(&pool, user_id, &req.goal).await?;
+    Ok(Json(AddGoalResponse { id }))
+}
+
+#[utoipa::path(
+    post, path = "/api/goals/delete", request_body = DeleteGoalRequest,
+    responses((status = 200))
+)]
+async fn delete_goal(
+    State(Writer(pool)): State<Writer>,
+    Json(req): Json<DeleteGoalRequest>,
+) -> ApiResult<()> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    goals::delete_goal(&pool, user_id, req.id).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post, path = "/api/webhooks", request_body = WebhooksRequest,
+    responses((status = 200, body = Vec<Webhook>))
+)]
+async fn list_webhooks(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<WebhooksRequest>,
+) -> ApiResult<Json<Vec<Webhook>>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    Ok(Json(webhook::list_webhooks(&pool, user_id).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/webhooks/add", request_body = AddWebhookRequest,
+    responses((status = 200, body = NewWebhook))
+)]
+async fn add_webhook(
+    State(state): State<AppState>,
+    Json(req): Json<AddWebhookRequest>,
+) -> ApiResult<Json<NewWebhook>> {
+    let pool = state.db.writer.clone();
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    Ok(Json(
+        webhook::add_webhook(&pool, state.webhooks.policy(), user_id, &req.url).await?,
+    ))
+}
+
+#[utoipa::path(
+    post, path = "/api/webhooks/delete", request_body = DeleteWebhookRequest,
+    responses((status = 200))
+)]
+async fn delete_webhook(
+    State(Writer(pool)): State<Writer>,
+    Json(req): Json<DeleteWebhookRequest>,
+) -> ApiResult<()> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    webhook::delete_webhook(&pool, user_id, req.id).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post, path = "/api/webhooks/deliveries", request_body = DeliveriesRequest,
+    responses((status = 200, body = Vec<Delivery>))
+)]
+async fn webhook_deliveries(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<DeliveriesRequest>,
+) -> ApiResult<Json<Vec<Delivery>>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    Ok(Json(
+        webhook::list_deliveries(&pool, user_id, req.id, req.limit).await?,
+    ))
+}
+
+#[utoipa::path(
+    post, path = "/api/recommend", request_body = RecommendRequest,
+    responses((status = 200, body = Recommendations))
+)]
+async fn recommend(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<RecommendRequest>,
+) -> ApiResult<Json<Recommendations>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    Ok(Json(
+        recommend::recommend(&pool, user_id, &req.filter).await?,
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/api/stats/{user}",
+    params(("user" = String, Path), StatsQuery),
+    responses((status = 200, body = Vec<GroupStats>))
+)]
+async fn public_stats(
+    State(pool): State<SqlitePool>,
+    Path(user): Path<String>,
+    Query(q): Query<StatsQuery>,
+) -> ApiResult<Json<Vec<GroupStats>>> {
+    let user_id = viewable_user(&pool, None, &user).await?;
+
+    Ok(Json(stats::user_stats(&pool, user_id, q.group_by).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/stats", request_body = StatsRequest,
+    responses((status = 200, body = Vec<GroupStats>))
+)]
+async fn user_stats(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<StatsRequest>,
+) -> ApiResult<Json<Vec<GroupStats>>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    let target = req.target.as_ref().unwrap_or(&req.user);
+    let target_id = viewable_user(&pool, Some(user_id), target).await?;
+
+    Ok(Json(
+        stats::user_stats(&pool, target_id, req.group_by).await?,
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/api/timeline/{user}",
+    params(("user" = String, Path), TimelineFilter),
+    responses((status = 200, body = Vec<TimelinePoint>))
+)]
+async fn public_timeline(
+    State(pool): State<SqlitePool>,
+    Path(user): Path<String>,
+    Query(filter): Query<TimelineFilter>,
+) -> ApiResult<Json<Vec<TimelinePoint>>> {
+    let user_id = viewable_user(&pool, None, &user).await?;
+    Ok(Json(timeline::timeline(&pool, user_id, &filter).await?))
+}
+
+#[utoipa::path(
+    post, path = "/api/timeline", request_body = TimelineRequest,
+    responses((status = 200, body = Vec<TimelinePoint>))
+)]
+async fn user_timeline(
+    State(pool): State<SqlitePool>,
+    Json(req): Json<TimelineRequest>,
+) -> ApiResult<Json<Vec<TimelinePoint>>> {
+    let user_id = auth_user(pool.clone(), &req.user, &req.password).await?;
+    let target = req.target.as_ref().unwrap_or(&req.user);
+    let target_id = viewable_user(&pool, Some(user_id), target).await?;
+    Ok(Json(
+        timeline::timeline(&pool, target_id, &req.filter).await?,
+    ))
+}
+
+#[utoipa::path(
+    get, path = "/api/health", responses((status = 200))
+)]
+async fn health() -> ApiResult<()> {
+    Ok(())
+}
+
+/// 配備したイメージが実際に処理できるか (DB・マイグレーション・ダンプの置き場所・楽曲一覧)
+#[utoipa::path(
+    get, path = "/api/ready",
+    responses(
+        (status = 200, body = ReadinessReport),
+        (status = 503, description = "Some checks failed", body = ReadinessReport),
+    )
+)]
+async fn ready(State(state): State<AppState>) -> ReadinessReport {
+    let (database, migrations, storage, catalog) = tokio::join!(
+        readiness::run(readiness::check_database(&state.db)),
+        readiness::run(readiness::check_migrations(&state.db.reader)),
+        readiness::run(readiness::check_storage(&state.storage)),
+        readiness::run(async { readiness::check_catalog(&*state.catalog.current().await?) }),
+    );
+    ReadinessReport::new([
+        ("database", database),
+        ("migrations", migrations),
+        ("storage", storage),
+        ("catalog", catalog),
+    ])
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "DDR score data API"),
+    paths(
+        update_score,
+        import_scores,
+        dump_user_data,
+        dump_status,
+        user_data,
+        shared_data,
+        update_settings,
+        list_goals,
+        add_goal,
+        delete_goal,
+        list_webhooks,
+        add_webhook,
+        delete_webhook,
+        webhook_deliveries,
+        recommend,
+        user_stats,
+        public_stats,
+        user_timeline,
+        public_timeline,
+        chart_leaderboard,
+        level_leaderboard,
+        health,
+        ready
+    ),
+    modifiers(&ErrorResponses)
+)]
+pub struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// 前の呼び出しで終わらなかったバックグラウンドの処理を再開する
+///
+/// Lambda ではレスポンスを返すと止まるため、呼び出しのたびに続きを動かす
+async fn resume_jobs(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    state.dump_queue.resume();
+    state.webhooks.resume();
+    next.run(request).await
+}
+
+pub fn router(state: AppState) -> Router {
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers(cors::Any)
+        .allow_origin(cors::Any);
+
+    let app = Router::new()
+        .route("/api/health", get(health))
+        .route("/api/ready", get(ready))
+        .route("/api/openapi.json", get(openapi))
+        .route("/api/update_score", post(update_score))
+        .route("/api/import", post(import_scores))
+        .route("/api/dump_user_data", post(dump_user_data))
+        .route("/api/dump_status", post(dump_status))
+        .route("/api/user_data", post(user_data))
+        .route("/api/shared/{token}/{file}", get(shared_data))
+        .route("/api/update_settings", post(update_settings))
+        .route("/api/goals", post(list_goals))
+        .route("/api/goals/add", post(add_goal))
+        .route("/api/goals/delete", post(delete_goal))
+        .route("/api/webhooks", post(list_webhooks))
+        .route("/api/webhooks/add", post(add_webhook))
+        .route("/api/webhooks/delete", post(delete_webhook))
+        .route("/api/webhooks/deliveries", post(webhook_deliveries))
+        .route("/api/recommend", post(recommend))
+        .route("/api/stats", post(user_stats))
+        .route("/api/stats/{user}", get(public_stats))
+        .route("/api/timeline", post(user_timeline))
+        .route("/api/timeline/{user}", get(public_timeline))
+        .route("/api/leaderboard/chart/{chart_id}", get(chart_leaderboard))
+        .route("/api/leaderboard/level/{level}", get(level_leaderboard));
+    let app = app.route_layer(middleware::from_fn_with_state(state.clone(), resume_jobs));
+    telemetry::instrument(app, "public", state.metrics.clone())
+        .layer(cors)
+        .with_state(state)
+}
@@ -0,0 +1,209 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use rand::{distr::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::NotFound;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Rivals,
+    Private,
+}
+
+impl FromStr for Visibility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "public" => Ok(Self::Public),
+            "rivals" => Ok(Self::Rivals),
+            "private" => Ok(Self::Private),
+            _ => Err(anyhow!("Unknown visibility {}", s)),
+        }
+    }
+}
+
+impl Display for Visibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Public => write!(f, "public"),
+            Self::Rivals => write!(f, "rivals"),
+            Self::Private => write!(f, "private"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UserSettings {
+    pub leaderboard: bool,
+    pub visibility: Visibility,
+    pub share_token: Option<String>,
+    pub rivals: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct UserSettingsPatch {
+    pub leaderboard: Option<bool>,
+    pub visibility: Option<Visibility>,
+    /// true なら共有トークンを (再) 発行し、false なら失効させる
+    pub share: Option<bool>,
+    /// 公開範囲が `rivals` のときに閲覧を許可するユーザー名 (全置き換え)
+    pub rivals: Option<Vec<String>>,
+}
+
+const SHARE_TOKEN_LEN: usize = 32;
+
+fn generate_share_token() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(SHARE_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+pub async fn load_settings(pool: &SqlitePool, user_id: i64) -> Result<UserSettings> {
+    let row = sqlx::query!(
+        r"select leaderboard, visibility, share_token from user_setting where user = ?",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let rivals = sqlx::query!(
+        r"select user.name from rival inner join user on user.id = rival.rival where rival.user = ?",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.name)
+    .collect();
+
+    let mut settings = match row {
+        Some(r) => UserSettings {
+            leaderboard: r.leaderboard != 0,
+            visibility: r.visibility.parse()?,
+            share_token: r.share_token,
+            rivals: vec![],
+        },
+        None => UserSettings::default(),
+    };
+    settings.rivals = rivals;
+    Ok(settings)
+}
+
+pub async fn update_settings(
+    pool: &SqlitePool,
+    user_id: i64,
+    patch: &UserSettingsPatch,
+) -> Result<UserSettings> {
+    let mut settings = load_settings(pool, user_id).await?;
+    if let Some(leaderboard) = patch.leaderboard {
+        settings.leaderboard = leaderboard;
+    }
+    if let Some(visibility) = patch.visibility {
+        settings.visibility = visibility;
+    }
+    match patch.share {
+        Some(true) => settings.share_token = Some(generate_share_token()),
+        Some(false) => settings.share_token = None,
+        None => {}
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let visibility = settings.visibility.to_string();
+    sqlx::query!(
+        r"insert into user_setting (user, leaderboard, visibility, share_token) values (?, ?, ?, ?)
+        on conflict (user) do update set
+            leaderboard = excluded.leaderboard,
+            visibility = excluded.visibility,
+            share_token = excluded.share_token",
+        user_id,
+        settings.leaderboard,
+        visibility,
+        settings.share_token
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(rivals) = &patch.rivals {
+        sqlx::query!(r"delete from rival where user = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut names = vec![];
+        for name in rivals {
+            let rival = sqlx::query!(r"select id, name from user where name = ?", name)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| NotFound(format!("Unknown user: {}", name)))?;
+            if rival.id == user_id || names.contains(&rival.name) {
+                continue;
+            }
+            sqlx::query!(
+                r"insert into rival (user, rival) values (?, ?)",
+                user_id,
+                rival.id
+            )
+            .execute(&mut *tx)
+            .await?;
+            names.push(rival.name);
+        }
+        settings.rivals = names;
+    }
+
+    tx.commit().await?;
+
+    Ok(settings)
+}
+
+/// `viewer` (未認証なら None) が `target` のデータを閲覧できるか
+pub async fn can_view(pool: &SqlitePool, viewer: Option<i64>, target: i64) -> Result<bool> {
+    if viewer == Some(target) {
+        return Ok(true);
+    }
+
+    let visibility = sqlx::query!(
+        r"select visibility from user_setting where user = ?",
+        target
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|r| r.visibility.parse::<Visibility>())
+    .transpose()?
+    .unwrap_or_default();
+
+    match (visibility, viewer) {
+        (Visibility::Public, _) => Ok(true),
+        (Visibility::Rivals, Some(viewer)) => {
+            let rival = sqlx::query!(
+                r"select id from rival where user = ? and rival = ?",
+                target,
+                viewer
+            )
+            .fetch_optional(pool)
+            .await?;
+            Ok(rival.is_some())
+        }
+        _ => Ok(false),
+    }
+}
+
+/// 共有トークンからユーザーを引く
+pub async fn user_by_share_token(pool: &SqlitePool, token: &str) -> Result<i64> {
+    sqlx::query!(
+        r"select user from user_setting where share_token = ?",
+        token
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|r| r.user)
+    .ok_or_else(|| NotFound("Invalid share token".to_owned()).into())
+}
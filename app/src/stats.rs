@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{recommend::MAX_FLARE_RANK, ClearKind, ClearRank, Difficulty};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsGroup {
+    #[default]
+    Level,
+    Difficulty,
+    Version,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Count {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GroupStats {
+    pub key: String,
+    pub charts: i64,
+    pub played: i64,
+    pub clear_kinds: Vec<Count>,
+    pub clear_ranks: Vec<Count>,
+    pub flare_ranks: Vec<Count>,
+    pub average_score: Option<f64>,
+    pub median_score: Option<f64>,
+}
+
+#[derive(Default)]
+struct Acc {
+    charts: i64,
+    kinds: BTreeMap<usize, i64>,
+    ranks: BTreeMap<usize, i64>,
+    flares: BTreeMap<i64, i64>,
+    scores: Vec<i64>,
+}
+
+impl Acc {
+    fn finish(mut self, key: String) -> GroupStats {
+        self.scores.sort_unstable();
+        let n = self.scores.len();
+        let average_score = (n > 0).then(|| self.scores.iter().sum::<i64>() as f64 / n as f64);
+        let median_score = (n > 0).then(|| {
+            if n % 2 == 1 {
+                self.scores[n / 2] as f64
+            } else {
+                (self.scores[n / 2 - 1] + self.scores[n / 2]) as f64 / 2.0
+            }
+        });
+
+        GroupStats {
+            key,
+            charts: self.charts,
+            played: n as i64,
+            clear_kinds: ClearKind::ALL
+                .iter()
+                .map(|k| Count {
+                    value: k.to_string(),
+                    count: self.kinds.get(&(*k as usize)).copied().unwrap_or(0),
+                })
+                .collect(),
+            clear_ranks: ClearRank::ALL
+                .iter()
+                .map(|r| Count {
+                    value: r.to_string(),
+                    count: self.ranks.get(&(*r as usize)).copied().unwrap_or(0),
+                })
+                .collect(),
+            flare_ranks: (0..=MAX_FLARE_RANK)
+                .rev()
+                .map(|f| Count {
+                    value: f.to_string(),
+                    count: self.flares.get(&f).copied().unwrap_or(0),
+                })
+                .collect(),
+            average_score,
+            median_score,
+        }
+    }
+}
+
+pub async fn user_stats(
+    pool: &SqlitePool,
+    user_id: i64,
+    group: StatsGroup,
+) -> Result<Vec<GroupStats>> {
+    let rows = sqlx::query!(
+        r"select
+            chart.difficulty,
+            chart.level,
+            song.ver,
+            bs.score,
+            bs.clear_rank,
+            bs.clear_kind,
+            bs.flare_rank
+        from
+            chart
+        inner join song on song.id = chart.song
+        left join best on best.chart = chart.id and best.user = ?
+        left join score as bs on bs.id = best.score",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // レベル・難易度は数値順、バージョンは名前順
+    let mut groups: BTreeMap<(i64, String), Acc> = BTreeMap::new();
+    for r in rows {
+        let key = match group {
+            StatsGroup::Level => (r.level, r.level.to_string()),
+            StatsGroup::Difficulty => (
+                r.difficulty,
+                Difficulty::ALL
+                    .get(r.difficulty as usize)
+                    .map_or(r.difficulty.to_string(), |d| d.to_string()),
+            ),
+            StatsGroup::Version => (0, r.ver),
+        };
+        let acc = groups.entry(key).or_default();
+        acc.charts += 1;
+
+        if let Some(k) = r
+            .clear_kind
+            .as_ref()
+            .and_then(|k| k.parse::<ClearKind>().ok())
+        {
+            *acc.kinds.entry(k as usize).or_default() += 1;
+        }
+        if let Some(rk) = r
+            .clear_rank
+            .as_ref()
+            .and_then(|r| r.parse::<ClearRank>().ok())
+        {
+            *acc.ranks.entry(rk as usize).or_default() += 1;
+        }
+        if let Some(f) = r.flare_rank {
+            *acc.flares.entry(f).or_default() += 1;
+        }
+        if let Some(s) = r.score {
+            acc.scores.push(s);
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|((_, key), acc)| acc.finish(key))
+        .collect())
+}
@@ -0,0 +1,412 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::{
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use tokio::io::AsyncWriteExt;
+
+/// マルチパートアップロードの 1 パートの大きさ (S3 の下限は 5 MiB)
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// バックアップなどの保存先 (ローカルのディレクトリまたは S3)
+#[derive(Debug, Clone)]
+pub enum Storage {
+    Local(PathBuf),
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+impl Storage {
+    /// `s3://bucket/prefix` または ディレクトリのパス
+    pub async fn from_url(url: &str) -> Result<Self> {
+        if let Some(rest) = url.strip_prefix("s3://") {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                return Err(anyhow!("Invalid storage url: {}", url));
+            }
+            let config = aws_config::load_from_env().await;
+            Ok(Self::S3 {
+                client: aws_sdk_s3::Client::new(&config),
+                bucket: bucket.to_owned(),
+                prefix: prefix.trim_end_matches('/').to_owned(),
+            })
+        } else {
+            Ok(Self::Local(PathBuf::from(url)))
+        }
+    }
+
+    fn s3_key(prefix: &str, key: &str) -> String {
+        if prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}/{}", prefix, key)
+        }
+    }
+
+    pub async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<()> {
+        match self {
+            Self::Local(dir) => {
+                let dest = dir.join(key);
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(dest, body).await?;
+            }
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(prefix, key))
+                    .content_type(content_type)
+                    .body(body.into())
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `key` の内容 (存在しなければ None)
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Local(dir) => match tokio::fs::read(dir.join(key)).await {
+                Ok(raw) => Ok(Some(raw)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let res = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(prefix, key))
+                    .send()
+                    .await;
+                match res {
+                    Ok(obj) => Ok(Some(obj.body.collect().await?.to_vec())),
+                    Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    /// 少しずつ書き込んで `key` に保存する
+    pub async fn upload(&self, key: &str, content_type: &str) -> Result<Upload> {
+        match self {
+            Self::Local(dir) => {
+                let dest = dir.join(key);
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let mut tmp = dest.as_os_str().to_owned();
+                tmp.push(".tmp");
+                let tmp = PathBuf::from(tmp);
+                Ok(Upload::Local {
+                    file: tokio::fs::File::create(&tmp).await?,
+                    tmp,
+                    dest,
+                })
+            }
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => Ok(Upload::S3(Box::new(S3Upload {
+                client: client.clone(),
+                bucket: bucket.clone(),
+                key: Self::s3_key(prefix, key),
+                content_type: content_type.to_owned(),
+                upload_id: None,
+                parts: vec![],
+                buf: vec![],
+            }))),
+        }
+    }
+
+    /// ファイルを `key` に保存する
+    pub async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        match self {
+            Self::Local(dir) => {
+                let dest = dir.join(key);
+                if let Some(parent) = dest.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::copy(path, dest).await?;
+            }
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(prefix, key))
+                    .body(ByteStream::from_path(path).await?)
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `key` の内容をファイルに書き出す
+    pub async fn get_file(&self, key: &str, path: &Path) -> Result<()> {
+        match self {
+            Self::Local(dir) => {
+                tokio::fs::copy(dir.join(key), path).await?;
+            }
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let mut body = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(prefix, key))
+                    .send()
+                    .await?
+                    .body;
+                let mut f = tokio::fs::File::create(path).await?;
+                while let Some(bytes) = body.try_next().await? {
+                    f.write_all(&bytes).await?;
+                }
+                f.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `dir` 直下のキーを名前順に返す
+    pub async fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        match self {
+            Self::Local(root) => {
+                let mut entries = match tokio::fs::read_dir(root.join(dir)).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_file() {
+                        if let Some(name) = entry.file_name().to_str() {
+                            keys.push(format!("{}/{}", dir, name));
+                        }
+                    }
+                }
+            }
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                let list_prefix = format!("{}/", Self::s3_key(prefix, dir));
+                let mut token = None;
+                loop {
+                    let res = client
+                        .list_objects_v2()
+                        .bucket(bucket)
+                        .prefix(&list_prefix)
+                        .set_continuation_token(token)
+                        .send()
+                        .await?;
+                    for obj in res.contents() {
+                        if let Some(name) = obj.key().and_then(|k| k.strip_prefix(&list_prefix)) {
+                            if !name.contains('/') {
+                                keys.push(format!("{}/{}", dir, name));
+                            }
+                        }
+                    }
+                    token = res.next_continuation_token().map(|t| t.to_owned());
+                    if token.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Self::Local(dir) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            Self::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                client
+                    .delete_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(prefix, key))
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct S3Upload {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+    content_type: String,
+    upload_id: Option<String>,
+    parts: Vec<CompletedPart>,
+    buf: Vec<u8>,
+}
+
+impl S3Upload {
+    async fn upload_part(&mut self) -> Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(id) => id.clone(),
+            None => {
+                let res = self
+                    .client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .content_type(&self.content_type)
+                    .send()
+                    .await?;
+                let id = res
+                    .upload_id()
+                    .ok_or_else(|| anyhow!("No upload id for {}", self.key))?
+                    .to_owned();
+                self.upload_id = Some(id.clone());
+                id
+            }
+        };
+
+        let part_number = self.parts.len() as i32 + 1;
+        let res = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(std::mem::take(&mut self.buf).into())
+            .send()
+            .await?;
+        self.parts.push(
+            CompletedPart::builder()
+                .set_e_tag(res.e_tag().map(|t| t.to_owned()))
+                .part_number(part_number)
+                .build(),
+        );
+        Ok(())
+    }
+}
+
+/// `Storage::upload` で始めた書き込み
+///
+/// S3 では 1 パートに満たなければ通常のアップロード、超えればマルチパートアップロードになる。
+/// ローカルでは一時ファイルに書いて `finish` で置き換える
+pub enum Upload {
+    Local {
+        file: tokio::fs::File,
+        tmp: PathBuf,
+        dest: PathBuf,
+    },
+    S3(Box<S3Upload>),
+}
+
+impl Upload {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        match self {
+            Self::Local { file, .. } => file.write_all(chunk).await?,
+            Self::S3(u) => {
+                u.buf.extend_from_slice(chunk);
+                if u.buf.len() >= PART_SIZE {
+                    u.upload_part().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(self) -> Result<()> {
+        match self {
+            Self::Local {
+                mut file,
+                tmp,
+                dest,
+            } => {
+                file.flush().await?;
+                file.sync_all().await?;
+                tokio::fs::rename(tmp, dest).await?;
+            }
+            Self::S3(mut u) => {
+                let Some(upload_id) = u.upload_id.clone() else {
+                    u.client
+                        .put_object()
+                        .bucket(&u.bucket)
+                        .key(&u.key)
+                        .content_type(&u.content_type)
+                        .body(std::mem::take(&mut u.buf).into())
+                        .send()
+                        .await?;
+                    return Ok(());
+                };
+                if !u.buf.is_empty() {
+                    u.upload_part().await?;
+                }
+                u.client
+                    .complete_multipart_upload()
+                    .bucket(&u.bucket)
+                    .key(&u.key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(std::mem::take(&mut u.parts)))
+                            .build(),
+                    )
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 書き込みを取りやめ、途中まで書いたものを消す
+    pub async fn abort(self) -> Result<()> {
+        match self {
+            Self::Local { file, tmp, .. } => {
+                drop(file);
+                tokio::fs::remove_file(tmp).await?;
+            }
+            Self::S3(u) => {
+                if let Some(upload_id) = &u.upload_id {
+                    u.client
+                        .abort_multipart_upload()
+                        .bucket(&u.bucket)
+                        .key(&u.key)
+                        .upload_id(upload_id)
+                        .send()
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
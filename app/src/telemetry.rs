@@ -0,0 +1,105 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::HeaderValue,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use rand::{distr::Alphanumeric, Rng};
+use tower_http::{
+    request_id::{MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::metrics::Metrics;
+
+/// ログの出力を設定する
+///
+/// 既定は 1 行 1 JSON (`LOG_FORMAT=text` で人が読む形式)。
+/// span の終了時にも出力するので、各処理の所要時間 (`time.busy`・`time.idle`) が残る
+pub fn init_tracing() {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_span_events(FmtSpan::CLOSE);
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("text") => builder.init(),
+        _ => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+/// `x-request-id` が無いリクエストに振る ID
+#[derive(Debug, Clone, Copy)]
+struct RandomRequestId;
+
+impl MakeRequestId for RandomRequestId {
+    fn make_request_id<B>(&mut self, _request: &axum::http::Request<B>) -> Option<RequestId> {
+        let id = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect::<String>();
+        HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+fn route(request: &Request) -> &str {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), |p| p.as_str())
+}
+
+/// リクエストごとの span (`user` は認証したときに埋める)
+fn request_span(request: &Request) -> tracing::Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        route = route(request),
+        user = tracing::field::Empty,
+    )
+}
+
+async fn track(
+    State((metrics, service)): State<(Metrics, &'static str)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = route(&request).to_owned();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.record_request(service, &route, response.status().as_u16(), start.elapsed());
+    response
+}
+
+/// リクエスト ID・span・メトリクスを付ける
+///
+/// ルートが決まってから動かすため `route_layer` で付ける (存在しないパスは記録しない)
+pub fn instrument<S>(router: Router<S>, service: &'static str, metrics: Metrics) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    // 後に付けたものほど外側
+    router
+        .route_layer(middleware::from_fn_with_state((metrics, service), track))
+        .route_layer(PropagateRequestIdLayer::x_request_id())
+        .route_layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .route_layer(SetRequestIdLayer::x_request_id(RandomRequestId))
+}
+
+/// 認証したユーザーをリクエストの span に記録する
+pub fn record_user(user: &str) {
+    tracing::Span::current().record("user", user);
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Days, FixedOffset, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{stats::Count, ClearKind, ClearRank};
+
+/// プレイ日の区切りに使うタイムゾーン (JST)
+const PLAY_DAY_OFFSET: i32 = 9 * 3600;
+
+/// UNIX 時間のプレイ日 (JST)
+pub fn play_day(timestamp: i64) -> Result<NaiveDate> {
+    let tz = FixedOffset::east_opt(PLAY_DAY_OFFSET).unwrap();
+    Ok(DateTime::from_timestamp(timestamp, 0)
+        .ok_or_else(|| anyhow!("Invalid timestamp {}", timestamp))?
+        .with_timezone(&tz)
+        .date_naive())
+}
+
+/// プレイ日の始まりの UNIX 時間
+fn play_day_start(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .and_utc()
+        .timestamp()
+        - PLAY_DAY_OFFSET as i64
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Interval {
+    #[default]
+    Daily,
+    Weekly,
+}
+
+impl Interval {
+    fn period_start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Daily => date,
+            Self::Weekly => date
+                .checked_sub_days(Days::new(date.weekday().num_days_from_monday() as u64))
+                .unwrap_or(date),
+        }
+    }
+}
+
+/// `from`・`to` はプレイ日 (JST)
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TimelineFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub interval: Interval,
+    pub level: Option<i64>,
+    pub min_level: Option<i64>,
+    pub max_level: Option<i64>,
+    pub difficulty: Option<i64>,
+}
+
+/// 期間末時点の状態 (更新のなかった期間は含まない)
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TimelinePoint {
+    pub period: NaiveDate,
+    pub improvements: i64,
+    pub clear_kinds: Vec<Count>,
+    pub clear_ranks: Vec<Count>,
+    pub total_flare_skill: i64,
+}
+
+/// 譜面ごとのその時点の自己ベスト (項目ごとに最も良い値)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ChartState {
+    score: Option<i64>,
+    clear_kind: Option<ClearKind>,
+    clear_rank: Option<ClearRank>,
+    flare_skill: i64,
+}
+
+impl ChartState {
+    /// `record` と項目ごとに良い方を取り、良くなった項目があれば true
+    ///
+    /// 取り込みでは古い日時の記録が後から加わるので、最新の行ではなく値を比べる
+    fn merge(&mut self, record: ChartState) -> bool {
+        // クリア種別・ランクは良いものほど小さい
+        fn better<T: Copy>(cur: Option<T>, new: Option<T>, key: impl Fn(T) -> i64) -> Option<T> {
+            match (cur, new) {
+                (Some(c), Some(n)) if key(n) < key(c) => Some(n),
+                (None, n) => n,
+                (c, _) => c,
+            }
+        }
+
+        let merged = ChartState {
+            score: self.score.max(record.score),
+            clear_kind: better(self.clear_kind, record.clear_kind, |k| k as i64),
+            clear_rank: better(self.clear_rank, record.clear_rank, |r| r as i64),
+            flare_skill: self.flare_skill.max(record.flare_skill),
+        };
+        let improved = merged != *self;
+        *self = merged;
+        improved
+    }
+}
+
+fn snapshot(
+    period: NaiveDate,
+    improvements: i64,
+    states: &HashMap<i64, ChartState>,
+) -> TimelinePoint {
+    let mut kinds = [0; ClearKind::ALL.len()];
+    let mut ranks = [0; ClearRank::ALL.len()];
+    let mut total_flare_skill = 0;
+    for s in states.values() {
+        if let Some(k) = s.clear_kind {
+            kinds[k as usize] += 1;
+        }
+        if let Some(r) = s.clear_rank {
+            ranks[r as usize] += 1;
+        }
+        total_flare_skill += s.flare_skill;
+    }
+
+    TimelinePoint {
+        period,
+        improvements,
+        clear_kinds: ClearKind::ALL
+            .iter()
+            .map(|k| Count {
+                value: k.to_string(),
+                count: kinds[*k as usize],
+            })
+            .collect(),
+        clear_ranks: ClearRank::ALL
+            .iter()
+            .map(|r| Count {
+                value: r.to_string(),
+                count: ranks[*r as usize],
+            })
+            .collect(),
+        total_flare_skill,
+    }
+}
+
+pub async fn timeline(
+    pool: &SqlitePool,
+    user_id: i64,
+    filter: &TimelineFilter,
+) -> Result<Vec<TimelinePoint>> {
+    // `from` より前の記録もその時点の状態を作るために読む
+    let until = filter
+        .to
+        .and_then(|to| to.succ_opt())
+        .map(play_day_start)
+        .unwrap_or(i64::MAX);
+    let rows = sqlx::query!(
+        r"select
+            score.chart,
+            chart.difficulty,
+            chart.level,
+            score.score,
+            score.clear_rank,
+            score.clear_kind,
+            score.flare_skill,
+            score.played_at
+        from
+            score
+        inner join chart on chart.id = score.chart
+        where
+            score.user = ?
+            and score.played_at < ?
+        order by score.played_at, score.id",
+        user_id,
+        until
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter(|r| {
+        filter.level.is_none_or(|l| l == r.level)
+            && filter.min_level.is_none_or(|l| l <= r.level)
+            && filter.max_level.is_none_or(|l| r.level <= l)
+            && filter.difficulty.is_none_or(|d| d == r.difficulty)
+    });
+
+    let mut states: HashMap<i64, ChartState> = HashMap::new();
+    let mut res = vec![];
+    let mut current: Option<(NaiveDate, i64)> = None;
+    for r in rows {
+        let date = play_day(r.played_at)?;
+        let period = filter.interval.period_start(date);
+
+        if let Some((p, n)) = current {
+            if p != period {
+                res.push(snapshot(p, n, &states));
+                current = None;
+            }
+        }
+
+        let improved = states.entry(r.chart).or_default().merge(ChartState {
+            score: r.score,
+            clear_kind: r.clear_kind.and_then(|k| k.parse().ok()),
+            clear_rank: r.clear_rank.and_then(|r| r.parse().ok()),
+            flare_skill: r.flare_skill.unwrap_or(0),
+        });
+
+        if improved && filter.from.is_none_or(|from| from <= date) {
+            current = Some((period, current.map_or(0, |(_, n)| n) + 1));
+        }
+    }
+    if let Some((p, n)) = current {
+        res.push(snapshot(p, n, &states));
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(score: i64, clear_kind: ClearKind, clear_rank: ClearRank) -> ChartState {
+        ChartState {
+            score: Some(score),
+            clear_kind: Some(clear_kind),
+            clear_rank: Some(clear_rank),
+            flare_skill: 0,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_the_best_of_each_field() {
+        let mut cur = state(950_000, ClearKind::FC, ClearRank::AAPlus);
+        // 取り込みで後から加わった古い記録の方がスコアは高い
+        assert!(cur.merge(ChartState {
+            flare_skill: 300,
+            ..state(990_000, ClearKind::Clear, ClearRank::AAA)
+        }));
+        assert_eq!(
+            cur,
+            ChartState {
+                flare_skill: 300,
+                ..state(990_000, ClearKind::FC, ClearRank::AAA)
+            }
+        );
+    }
+
+    #[test]
+    fn merge_ignores_worse_records() {
+        let mut cur = state(990_000, ClearKind::FC, ClearRank::AAA);
+        assert!(!cur.merge(state(900_000, ClearKind::Clear, ClearRank::AA)));
+        assert!(!cur.merge(ChartState::default()));
+        assert_eq!(cur, state(990_000, ClearKind::FC, ClearRank::AAA));
+    }
+
+    #[test]
+    fn merge_fills_missing_fields() {
+        let mut cur = ChartState::default();
+        assert!(cur.merge(state(800_000, ClearKind::Failed, ClearRank::E)));
+        assert_eq!(cur, state(800_000, ClearKind::Failed, ClearRank::E));
+    }
+}
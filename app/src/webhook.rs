@@ -0,0 +1,570 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{distr::Alphanumeric, Rng};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::{
+    db::Db,
+    job_queue::{self, JobRunner},
+    scores::Improvement,
+    Forbidden, NotFound,
+};
+
+pub const EVENT_PERSONAL_BEST: &str = "personal_best";
+
+const SECRET_LEN: usize = 32;
+const DELIVERY_ID_LEN: usize = 16;
+/// 失敗時に試す回数 (初回を含む)
+const MAX_ATTEMPTS: i64 = 5;
+/// 再試行までの待ち時間の初期値 (試すたびに倍にする)
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub created_at: String,
+}
+
+/// 登録直後だけ署名用の secret を返す
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    pub id: i64,
+    pub url: String,
+    pub secret: String,
+}
+
+/// 送信 1 回分の記録
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook: i64,
+    /// 同じ通知の再送で共通の ID
+    pub delivery: String,
+    pub event: String,
+    pub attempt: i64,
+    /// HTTP ステータス (接続できなければ None)
+    pub status: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Payload<'a> {
+    event: &'a str,
+    delivery: &'a str,
+    user: &'a str,
+    sent_at: String,
+    improvements: &'a [Improvement],
+}
+
+fn random_string(len: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// `{timestamp}.{body}` の HMAC-SHA256 (16 進)
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// 外部から届かないアドレス (ループバック・プライベート・リンクローカルなど) でないか
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(v6),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // 共有アドレス (CGNAT)
+        || (a == 100 && (64..128).contains(&b))
+        // IETF プロトコル割り当て
+        || (a == 192 && b == 0 && c == 0)
+        // ベンチマーク
+        || (a == 198 && (18..20).contains(&b))
+        // 予約済み
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // ユニークローカル
+        || (first & 0xfe00) == 0xfc00
+        // リンクローカル
+        || (first & 0xffc0) == 0xfe80
+        // ドキュメント用
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Webhook の送り先の制限
+///
+/// Lambda は VPC 内で動くため、内部のアドレス (メタデータ・DB など) に送れないようにする。
+/// 名前解決した結果も確かめるので、外部の名前が内部のアドレスを指していても送らない
+#[derive(Debug, Clone, Default)]
+pub struct WebhookPolicy {
+    /// 内部のアドレスでも送ってよいホスト (ローカル開発・テスト用)
+    pub allowed_hosts: Vec<String>,
+}
+
+impl WebhookPolicy {
+    /// `WEBHOOK_ALLOWED_HOSTS` (カンマ区切り) から作る
+    pub fn from_env() -> Self {
+        Self {
+            allowed_hosts: std::env::var("WEBHOOK_ALLOWED_HOSTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|h| h.trim().to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect(),
+        }
+    }
+
+    fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.allowed_hosts
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(host))
+    }
+
+    /// `host` の解決結果 `addrs` に送ってよいか
+    fn check_addrs(&self, host: &str, addrs: &[SocketAddr]) -> Result<()> {
+        if self.is_allowed_host(host) {
+            return Ok(());
+        }
+        if addrs.is_empty() {
+            return Err(anyhow!("Cannot resolve webhook host: {}", host));
+        }
+        if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+            return Err(Forbidden(format!(
+                "Webhook host {} resolves to a non-public address {}",
+                host,
+                addr.ip()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// スキームと送り先のアドレスを確かめる
+    pub async fn check_url(&self, url: &str) -> Result<()> {
+        let parsed = Url::parse(url)?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(anyhow!("Unsupported webhook url: {}", url));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow!("Unsupported webhook url: {}", url))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = match literal.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host, port)).await?.collect(),
+        };
+        self.check_addrs(host, &addrs)
+    }
+}
+
+/// 解決したアドレスを `WebhookPolicy` で確かめる名前解決
+///
+/// 登録時と送信時で名前の指す先が変わっていても (DNS rebinding) 内部には送らない
+struct CheckedResolver {
+    policy: Arc<WebhookPolicy>,
+}
+
+impl Resolve for CheckedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs = tokio::net::lookup_host((host, 0))
+                .await?
+                .collect::<Vec<_>>();
+            policy.check_addrs(host, &addrs)?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub async fn add_webhook(
+    pool: &SqlitePool,
+    policy: &WebhookPolicy,
+    user_id: i64,
+    url: &str,
+) -> Result<NewWebhook> {
+    policy.check_url(url).await?;
+
+    let secret = random_string(SECRET_LEN);
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let id = sqlx::query!(
+        r"insert into webhook (user, url, secret, created_at) values (?, ?, ?, ?) returning id",
+        user_id,
+        url,
+        secret,
+        now
+    )
+    .fetch_one(pool)
+    .await?
+    .id;
+
+    Ok(NewWebhook {
+        id,
+        url: url.to_owned(),
+        secret,
+    })
+}
+
+pub async fn delete_webhook(pool: &SqlitePool, user_id: i64, webhook_id: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let res = sqlx::query!(
+        r"delete from webhook where id = ? and user = ?",
+        webhook_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(NotFound(format!("Unknown webhook: {}", webhook_id)).into());
+    }
+    sqlx::query!(
+        r"delete from webhook_delivery where webhook = ?",
+        webhook_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(r"delete from webhook_job where webhook = ?", webhook_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn list_webhooks(pool: &SqlitePool, user_id: i64) -> Result<Vec<Webhook>> {
+    Ok(sqlx::query_as!(
+        Webhook,
+        r"select id, url, created_at from webhook where user = ? order by id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// 新しい順の送信記録
+pub async fn list_deliveries(
+    pool: &SqlitePool,
+    user_id: i64,
+    webhook_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<Delivery>> {
+    Ok(sqlx::query_as!(
+        Delivery,
+        r"select d.id, d.webhook, d.delivery, d.event, d.attempt, d.status, d.error, d.created_at
+        from webhook_delivery as d
+        inner join webhook on webhook.id = d.webhook
+        where webhook.user = ? and (? is null or d.webhook = ?)
+        order by d.id desc
+        limit ?",
+        user_id,
+        webhook_id,
+        webhook_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// 実行を始めた送信
+struct Claimed {
+    id: i64,
+    webhook: i64,
+    delivery: String,
+    body: Vec<u8>,
+    attempt: i64,
+    lease_until: i64,
+}
+
+/// Webhook の送信を DB に残し、バックグラウンドで送る
+///
+/// 失敗したら間隔を空けて `MAX_ATTEMPTS` 回まで再送し、試すたびに `webhook_delivery` に記録する
+#[derive(Debug, Clone)]
+pub struct WebhookQueue {
+    db: Db,
+    policy: Arc<WebhookPolicy>,
+    /// 送り先を `policy` で確かめ、リダイレクトには従わない
+    client: reqwest::Client,
+    /// このプロセスで送信中か
+    draining: Arc<AtomicBool>,
+}
+
+impl WebhookQueue {
+    pub fn new(db: Db, policy: WebhookPolicy) -> Result<Self> {
+        let policy = Arc::new(policy);
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(CheckedResolver {
+                policy: policy.clone(),
+            }))
+            .redirect(redirect::Policy::none())
+            .timeout(TIMEOUT)
+            .build()?;
+        Ok(Self {
+            db,
+            policy,
+            client,
+            draining: Arc::default(),
+        })
+    }
+
+    pub fn policy(&self) -> &WebhookPolicy {
+        &self.policy
+    }
+
+    /// 自己ベストの更新を登録済みの Webhook に送る
+    #[tracing::instrument(skip_all, fields(improvements = improvements.len()))]
+    pub async fn notify(
+        &self,
+        user_id: i64,
+        user: &str,
+        improvements: &[Improvement],
+    ) -> Result<()> {
+        if improvements.is_empty() {
+            return Ok(());
+        }
+        let hooks = sqlx::query!(r"select id from webhook where user = ?", user_id)
+            .fetch_all(&self.db.writer)
+            .await?;
+        if hooks.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let sent_at = now.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let next_attempt_at = now.timestamp();
+        let mut tx = self.db.writer.begin().await?;
+        for hook in hooks {
+            let delivery = random_string(DELIVERY_ID_LEN);
+            let body = serde_json::to_vec(&Payload {
+                event: EVENT_PERSONAL_BEST,
+                delivery: &delivery,
+                user,
+                sent_at: sent_at.clone(),
+                improvements,
+            })?;
+            sqlx::query!(
+                r"insert into webhook_job (webhook, delivery, body, next_attempt_at, created_at)
+                values (?, ?, ?, ?, ?)",
+                hook.id,
+                delivery,
+                body,
+                next_attempt_at,
+                sent_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        self.resume();
+        Ok(())
+    }
+
+    /// 送信待ちがあればバックグラウンドで送る
+    pub fn resume(&self) {
+        job_queue::resume(self);
+    }
+
+    /// 時刻の来た送信を 1 つ取る
+    async fn claim(&self) -> Result<Option<Claimed>> {
+        let now = Utc::now().timestamp();
+        let lease_until = job_queue::lease_until();
+        Ok(sqlx::query!(
+            r"update webhook_job set
+                lease_until = ?1,
+                attempts = attempts + 1
+            where id = (
+                select id from webhook_job
+                where next_attempt_at <= ?2 and (lease_until is null or lease_until <= ?2)
+                order by next_attempt_at, id limit 1
+            )
+            returning id, webhook, delivery, body, attempts",
+            lease_until,
+            now
+        )
+        .fetch_optional(&self.db.writer)
+        .await?
+        .map(|r| Claimed {
+            id: r.id,
+            webhook: r.webhook,
+            delivery: r.delivery,
+            body: r.body,
+            attempt: r.attempts,
+            lease_until,
+        }))
+    }
+
+    async fn attempt(&self, claimed: Claimed) -> Result<()> {
+        let hook = sqlx::query!(
+            r"select url, secret from webhook where id = ?",
+            claimed.webhook
+        )
+        .fetch_optional(&self.db.reader)
+        .await?;
+        // 送る前に Webhook が削除された
+        let Some(hook) = hook else {
+            sqlx::query!(r"delete from webhook_job where id = ?", claimed.id)
+                .execute(&self.db.writer)
+                .await?;
+            return Ok(());
+        };
+
+        let (status, error) = self
+            .send(&hook.url, &hook.secret, &claimed.delivery, &claimed.body)
+            .await;
+
+        let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        sqlx::query!(
+            r"insert into webhook_delivery (webhook, delivery, event, attempt, status, error, created_at)
+            values (?, ?, ?, ?, ?, ?, ?)",
+            claimed.webhook,
+            claimed.delivery,
+            EVENT_PERSONAL_BEST,
+            claimed.attempt,
+            status,
+            error,
+            now
+        )
+        .execute(&self.db.writer)
+        .await?;
+
+        // 送れたか諦めたら消し、そうでなければ再送の時刻を設定する
+        if error.is_none() || claimed.attempt >= MAX_ATTEMPTS {
+            if error.is_some() {
+                tracing::error!(
+                    webhook = claimed.webhook,
+                    delivery = claimed.delivery,
+                    "webhook gave up after {} attempts",
+                    claimed.attempt
+                );
+            }
+            sqlx::query!(
+                r"delete from webhook_job where id = ? and lease_until = ?",
+                claimed.id,
+                claimed.lease_until
+            )
+            .execute(&self.db.writer)
+            .await?;
+        } else {
+            let next_attempt_at = Utc::now().timestamp()
+                + job_queue::retry_delay(RETRY_DELAY, claimed.attempt).as_secs() as i64;
+            sqlx::query!(
+                r"update webhook_job set next_attempt_at = ?, lease_until = null
+                where id = ? and lease_until = ?",
+                next_attempt_at,
+                claimed.id,
+                claimed.lease_until
+            )
+            .execute(&self.db.writer)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// 署名を付けて 1 回送る (HTTP ステータスと、失敗ならエラー)
+    async fn send(
+        &self,
+        url: &str,
+        secret: &str,
+        delivery: &str,
+        body: &[u8],
+    ) -> (Option<i64>, Option<String>) {
+        // IP アドレスで書かれた URL は名前解決を通らないのでここで確かめる
+        if let Err(e) = self.policy.check_url(url).await {
+            return (None, Some(format!("{:#}", e)));
+        }
+        let timestamp = Utc::now().timestamp();
+        let res = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Event", EVENT_PERSONAL_BEST)
+            .header("X-Webhook-Delivery", delivery)
+            .header("X-Webhook-Timestamp", timestamp)
+            .header(
+                "X-Webhook-Signature",
+                format!("sha256={}", sign(secret, timestamp, body)),
+            )
+            .body(body.to_vec())
+            .send()
+            .await;
+        match res {
+            Ok(r) if r.status().is_success() => (Some(r.status().as_u16() as i64), None),
+            Ok(r) => (
+                Some(r.status().as_u16() as i64),
+                Some(format!("Unexpected status {}", r.status())),
+            ),
+            Err(e) => (None, Some(format!("{:#}", anyhow::Error::from(e)))),
+        }
+    }
+}
+
+impl JobRunner for WebhookQueue {
+    fn draining(&self) -> &AtomicBool {
+        &self.draining
+    }
+
+    async fn next_due(&self) -> Result<Option<i64>> {
+        let now = Utc::now().timestamp();
+        Ok(sqlx::query_scalar!(
+            r"select min(next_attempt_at) from webhook_job
+            where lease_until is null or lease_until <= ?",
+            now
+        )
+        .fetch_one(&self.db.reader)
+        .await?)
+    }
+
+    async fn run_next(&self) -> Result<bool> {
+        let Some(claimed) = self.claim().await? else {
+            return Ok(false);
+        };
+        self.attempt(claimed).await?;
+        Ok(true)
+    }
+}
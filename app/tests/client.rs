@@ -0,0 +1,233 @@
+mod common;
+
+use std::path::PathBuf;
+
+use app::{
+    api::RequestSongData,
+    client::{Client, ClientError},
+    export::ExportOptions,
+    leaderboard::LeaderboardKey,
+    metrics::Metrics,
+    scores::RequestScoreData,
+    server::{private, public},
+    settings::{UserSettingsPatch, Visibility},
+    storage::Storage,
+};
+use axum::Router;
+
+/// `router` を空いているポートで動かしてベース URL を返す
+async fn serve(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}/", addr)
+}
+
+fn score(title: &str, difficulty: &str, score: i64) -> RequestScoreData {
+    RequestScoreData {
+        title: title.to_owned(),
+        difficulty: difficulty.to_owned(),
+        score: Some(score),
+        ex_score: Some(score / 1000),
+        rank: Some("AA+".to_owned()),
+        clear_kind: Some("CLEAR".to_owned()),
+        flare_skill: None,
+        flare_rank: None,
+        played_at: None,
+    }
+}
+
+/// public と private を別々のポートで動かす (一時ディレクトリ, public の URL, private の URL)
+async fn start(name: &str) -> (PathBuf, String, String) {
+    let dir = common::temp_dir(name);
+    let db = common::temp_db(&dir).await;
+    let metrics = Metrics::default();
+    let public_state = public::AppState::new(
+        db.clone(),
+        Storage::Local(dir.join("public")),
+        metrics.clone(),
+    )
+    .await
+    .unwrap();
+    let private_state = private::AppState::new(db, None, metrics);
+    let public_url = serve(public::router(public_state)).await;
+    let private_url = serve(private::router(private_state)).await;
+    (dir, public_url, private_url)
+}
+
+async fn add_paranoia(admin: &Client) {
+    let res = admin
+        .add_songs(vec![RequestSongData {
+            name: "PARANOiA".to_owned(),
+            version: "1st".to_owned(),
+            levels: [Some(4), Some(8), Some(11), Some(14), None],
+        }])
+        .await
+        .unwrap();
+    assert_eq!(res.inserted_songs, 1);
+    assert_eq!(res.inserted_charts, 4);
+}
+
+#[tokio::test]
+async fn drives_public_and_private_api() {
+    let (dir, public_url, private_url) = start("client").await;
+    let admin = Client::new(&private_url).unwrap();
+    add_paranoia(&admin).await;
+
+    admin.add_user("alice", "alice-pw").await.unwrap();
+    admin.add_user("bob", "bob-pw").await.unwrap();
+    assert!(matches!(
+        admin.add_user("alice", "other").await,
+        Err(ClientError::Conflict(_))
+    ));
+
+    let mut alice = Client::new(&public_url).unwrap();
+    assert!(matches!(
+        alice.update_score(vec![], None).await,
+        Err(ClientError::NotLoggedIn)
+    ));
+    assert!(alice.login("alice", "wrong").await.is_err());
+    alice.login("alice", "alice-pw").await.unwrap();
+    assert_eq!(alice.user(), Some("alice"));
+
+    let res = alice
+        .update_score(vec![score("PARANOiA", "EXPERT", 990_000)], None)
+        .await
+        .unwrap();
+    assert_eq!(res.updated, 1);
+    assert!(res.errors.is_empty());
+    assert_eq!(res.improvements.len(), 1);
+    let chart_id = res.improvements[0].chart_id;
+
+    // 同じ記録をもう一度送っても自己ベストは変わらない
+    let res = alice
+        .update_score(vec![score("PARANOiA", "EXPERT", 990_000)], None)
+        .await
+        .unwrap();
+    assert!(res.improvements.is_empty());
+
+    let settings = alice
+        .update_settings(UserSettingsPatch {
+            leaderboard: Some(true),
+            share: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let token = settings.share_token.expect("share token was not issued");
+
+    let anonymous = Client::new(&public_url).unwrap();
+    let data = anonymous.shared_data(&token, "bests.jsonl").await.unwrap();
+    let data = String::from_utf8(data).unwrap();
+    assert!(data.contains("PARANOiA"));
+    assert!(data.contains("990000"));
+    assert!(matches!(
+        anonymous.shared_data("invalid", "bests.jsonl").await,
+        Err(ClientError::NotFound(_))
+    ));
+
+    let mut bob = Client::new(&public_url).unwrap();
+    bob.login("bob", "bob-pw").await.unwrap();
+    bob.update_score(vec![score("PARANOiA", "EXPERT", 950_000)], None)
+        .await
+        .unwrap();
+
+    // 参加していないユーザーは載らない
+    let board = anonymous
+        .chart_leaderboard(chart_id, LeaderboardKey::Score)
+        .await
+        .unwrap();
+    assert_eq!(board.title, "PARANOiA");
+    assert_eq!(board.level, 14);
+    let users = board
+        .entries
+        .iter()
+        .map(|e| (e.rank, e.user.as_str(), e.score))
+        .collect::<Vec<_>>();
+    assert_eq!(users, vec![(1, "alice", Some(990_000))]);
+
+    bob.update_settings(UserSettingsPatch {
+        leaderboard: Some(true),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let board = anonymous
+        .chart_leaderboard(chart_id, LeaderboardKey::Score)
+        .await
+        .unwrap();
+    let users = board
+        .entries
+        .iter()
+        .map(|e| (e.rank, e.user.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(users, vec![(1, "alice"), (2, "bob")]);
+
+    let summary = anonymous
+        .level_leaderboard(14, LeaderboardKey::Score)
+        .await
+        .unwrap();
+    assert_eq!(summary.level, 14);
+    let users = summary
+        .entries
+        .iter()
+        .map(|e| (e.rank, e.user.as_str(), e.charts, e.total_score))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        users,
+        vec![(1, "alice", 1, 990_000), (2, "bob", 1, 950_000)]
+    );
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn unpublishes_dumps_when_profile_turns_private() {
+    let (dir, public_url, private_url) = start("unpublish").await;
+    let admin = Client::new(&private_url).unwrap();
+    add_paranoia(&admin).await;
+    admin.add_user("alice", "alice-pw").await.unwrap();
+
+    let mut alice = Client::new(&public_url).unwrap();
+    alice.login("alice", "alice-pw").await.unwrap();
+    alice
+        .update_score(vec![score("PARANOiA", "EXPERT", 990_000)], None)
+        .await
+        .unwrap();
+    let published = alice
+        .dump_user_data(ExportOptions::default())
+        .await
+        .unwrap()
+        .expect("public profile was not published");
+
+    let data_dir = dir.join("public/scores/alice/data");
+    assert!(data_dir.join("manifest.json").exists());
+    for name in published.manifest.files.keys() {
+        assert!(data_dir.join(name).exists(), "{} was not published", name);
+    }
+
+    // カラムを絞った公開ダンプで正規のファイルを上書きしない
+    let bests = std::fs::read(data_dir.join("bests.tsv.gz")).unwrap();
+    assert!(alice
+        .dump_user_data(ExportOptions {
+            columns: Some(vec!["id".to_owned(), "score".to_owned()]),
+            ..Default::default()
+        })
+        .await
+        .is_err());
+    assert_eq!(std::fs::read(data_dir.join("bests.tsv.gz")).unwrap(), bests);
+
+    alice
+        .update_settings(UserSettingsPatch {
+            visibility: Some(Visibility::Private),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let remaining = std::fs::read_dir(&data_dir)
+        .map(|entries| entries.count())
+        .unwrap_or(0);
+    assert_eq!(remaining, 0);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,35 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use app::db::{self, Db, DbConfig, JournalMode};
+
+/// テストごとの一時ディレクトリ (前回の残りは消す)
+pub fn temp_dir(name: &str) -> PathBuf {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "app-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `dir` に DB を作ってマイグレーションを適用する
+pub async fn temp_db(dir: &std::path::Path) -> Db {
+    let url = format!("sqlite://{}", dir.join("test.db").display());
+    db::migrate(&url).await.unwrap();
+    db::connect(&DbConfig {
+        url,
+        journal_mode: JournalMode::Wal,
+        read_connections: 2,
+        busy_timeout: std::time::Duration::from_secs(5),
+        idle_timeout: None,
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use app::{
+    job_queue::{self, JobRunner},
+    scores::{Improvement, ScoreField, ScoreValues},
+    webhook::{self, WebhookPolicy, WebhookQueue},
+};
+use axum::{body::Bytes, extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// 受け取ったリクエストのヘッダーと本文
+type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+/// 1 回目は 500、2 回目以降は 200 を返す受け口を立てて URL を返す
+async fn start_receiver(received: Received) -> String {
+    async fn receive(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        received.push((headers, body));
+        if received.len() == 1 {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state(received);
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}/hook", addr)
+}
+
+fn improvement() -> Improvement {
+    let current = ScoreValues {
+        score: Some(990_000),
+        ex_score: Some(1200),
+        clear_rank: Some("AAA".to_owned()),
+        clear_kind: Some("PFC".to_owned()),
+        flare_rank: Some(10),
+        flare_skill: Some(500),
+    };
+    Improvement {
+        chart_id: 1,
+        title: "test".to_owned(),
+        difficulty: "EXPERT".to_owned(),
+        level: 15,
+        previous: None,
+        current,
+        improved: vec![ScoreField::Score],
+    }
+}
+
+#[tokio::test]
+async fn delivers_signed_payload_and_retries_after_failure() {
+    let dir = common::temp_dir("webhook");
+    let db = common::temp_db(&dir).await;
+    let user_id = sqlx::query_scalar::<_, i64>(
+        "insert into user (name, password_hash) values ('alice', '') returning id",
+    )
+    .fetch_one(&db.writer)
+    .await
+    .unwrap();
+
+    let received = Received::default();
+    let url = start_receiver(received.clone()).await;
+    let policy = WebhookPolicy {
+        allowed_hosts: vec!["127.0.0.1".to_owned()],
+    };
+
+    // 許可していなければループバックには登録できない
+    assert!(
+        webhook::add_webhook(&db.writer, &WebhookPolicy::default(), user_id, &url)
+            .await
+            .is_err()
+    );
+    let hook = webhook::add_webhook(&db.writer, &policy, user_id, &url)
+        .await
+        .unwrap();
+
+    let queue = WebhookQueue::new(db.clone(), policy).unwrap();
+    queue
+        .notify(user_id, "alice", &[improvement()])
+        .await
+        .unwrap();
+
+    // 1 回目の失敗から再試行の待ち時間を経て 2 回目が届き、送信待ちが消えるまで待つ
+    let deliveries = tokio::time::timeout(Duration::from_secs(20), async {
+        loop {
+            let deliveries = webhook::list_deliveries(&db.reader, user_id, Some(hook.id), 10)
+                .await
+                .unwrap();
+            let pending = sqlx::query_scalar::<_, i64>("select count(*) from webhook_job")
+                .fetch_one(&db.reader)
+                .await
+                .unwrap();
+            if deliveries.len() >= 2 && pending == 0 {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("webhook was not retried");
+
+    // 新しい順
+    assert_eq!(deliveries.len(), 2);
+    let (retry, first) = (&deliveries[0], &deliveries[1]);
+    assert_eq!(first.attempt, 1);
+    assert_eq!(first.status, Some(500));
+    assert!(first.error.is_some());
+    assert_eq!(retry.attempt, 2);
+    assert_eq!(retry.status, Some(200));
+    assert_eq!(retry.error, None);
+    assert_eq!(first.delivery, retry.delivery);
+
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 2);
+    for (headers, body) in &received {
+        let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_owned();
+        assert_eq!(header("x-webhook-event"), webhook::EVENT_PERSONAL_BEST);
+        assert_eq!(header("x-webhook-delivery"), first.delivery);
+
+        let timestamp = header("x-webhook-timestamp");
+        let mut mac = Hmac::<Sha256>::new_from_slice(hook.secret.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        let expected = format!("sha256={:x}", mac.finalize().into_bytes());
+        assert_eq!(header("x-webhook-signature"), expected);
+
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["user"], "alice");
+        assert_eq!(payload["delivery"], first.delivery);
+        assert_eq!(payload["improvements"][0]["chart_id"], 1);
+    }
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn does_not_wait_for_jobs_leased_by_another_process() {
+    let dir = common::temp_dir("webhook-lease");
+    let db = common::temp_db(&dir).await;
+    let queue = WebhookQueue::new(db.clone(), WebhookPolicy::default()).unwrap();
+
+    // 他のプロセスが実行中の送信
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query(
+        "insert into webhook_job (webhook, delivery, body, next_attempt_at, lease_until, created_at)
+        values (1, 'leased', x'', ?, ?, '')",
+    )
+    .bind(now)
+    .bind(job_queue::lease_until())
+    .execute(&db.writer)
+    .await
+    .unwrap();
+    assert_eq!(queue.next_due().await.unwrap(), None);
+    assert!(!queue.run_next().await.unwrap());
+
+    // 実行していたプロセスが止まって期限が切れたら取り直す
+    sqlx::query("update webhook_job set lease_until = ?")
+        .bind(now - 1)
+        .execute(&db.writer)
+        .await
+        .unwrap();
+    assert_eq!(queue.next_due().await.unwrap(), Some(now));
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -46,8 +46,9 @@ resource "aws_lambda_function" "api" {
 
   environment {
     variables = {
-      "DATABASE_URL" = "sqlite:/mnt/efs/db/ddr_score.db"
-      "S3_BUCKET"    = aws_s3_bucket.s3_public.bucket
+      "DATABASE_URL"    = "sqlite:/mnt/efs/db/ddr_score.db"
+      "DB_JOURNAL_MODE" = "delete"
+      "S3_BUCKET"       = aws_s3_bucket.s3_public.bucket
     }
   }
 
@@ -96,7 +97,9 @@ resource "aws_lambda_function" "api_private" {
 
   environment {
     variables = {
-      "DATABASE_URL" = "sqlite:/mnt/efs/db/ddr_score.db"
+      "DATABASE_URL"    = "sqlite:/mnt/efs/db/ddr_score.db"
+      "DB_JOURNAL_MODE" = "delete"
+      "BACKUP_STORAGE"  = "/mnt/efs/backups"
     }
   }
 